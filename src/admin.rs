@@ -0,0 +1,181 @@
+use std::{collections::BTreeMap, net::TcpListener, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    analytics::{self, ProviderStats},
+    config::{group_config, group_configs, AdminConfig},
+    database::{BridgeInfo, DATABASE},
+    loop_frontline::FRONTLINE_STATE,
+    metrics,
+    provider::throttle::{group_health, Health},
+};
+
+/// The statuses an operator may force a bridge into.
+const FORCEABLE_STATUSES: &[&str] = &["frontline", "reserve", "blocked"];
+
+/// Serves the admin HTTP API until it fails. Must run within a tokio context, such as [async_compat::Compat].
+pub async fn serve_admin(cfg: AdminConfig) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(cfg.listen)?;
+    log::info!("admin API listening on {}", cfg.listen);
+    axum::Server::from_tcp(listener)?
+        .serve(router(cfg.token).into_make_service())
+        .await?;
+    Ok(())
+}
+
+fn router(token: String) -> Router {
+    Router::new()
+        .route("/groups", get(list_groups))
+        .route("/groups/:group/bridges", get(list_bridges))
+        .route("/groups/:group/stats", get(group_stats))
+        .route("/bridges/:bridge_id/status", post(force_status))
+        .route("/bridges/:bridge_id/retire", post(retire))
+        .layer(middleware::from_fn_with_state(Arc::new(token), authorize))
+        // added after the layer, so that Prometheus can scrape without the token
+        .route("/metrics", get(render_metrics))
+}
+
+async fn authorize<B>(
+    State(token): State<Arc<String>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        == Some(token.as_str());
+    if authorized {
+        next.run(req).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// An error, along with the status code it should be reported with.
+struct AdminError(StatusCode, String);
+
+impl From<anyhow::Error> for AdminError {
+    fn from(err: anyhow::Error) -> Self {
+        log::warn!("admin API error: {:?}", err);
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct GroupSummary {
+    counts: BTreeMap<String, i64>,
+    /// None for groups whose frontline loop has not started.
+    adjusted_frontline: Option<usize>,
+    overload: Option<f64>,
+    /// Only the providers that were tried so far.
+    providers: BTreeMap<String, Health>,
+}
+
+async fn list_groups() -> Result<Json<BTreeMap<String, GroupSummary>>, AdminError> {
+    let mut summaries = BTreeMap::new();
+    for group in group_configs().keys() {
+        let state = FRONTLINE_STATE.get(group).map(|s| *s);
+        summaries.insert(
+            group.clone(),
+            GroupSummary {
+                counts: DATABASE.status_counts(group).await?,
+                adjusted_frontline: state.map(|s| s.adjusted_frontline),
+                overload: state.and_then(|s| s.overload),
+                providers: group_health(group),
+            },
+        );
+    }
+    Ok(Json(summaries))
+}
+
+async fn list_bridges(Path(group): Path<String>) -> Result<Json<Vec<BridgeInfo>>, AdminError> {
+    if group_config(&group).is_none() {
+        return Err(AdminError(
+            StatusCode::NOT_FOUND,
+            format!("no group {group}"),
+        ));
+    }
+    let mut bridges: Vec<BridgeInfo> = DATABASE
+        .all_bridges()
+        .await?
+        .into_iter()
+        .filter(|b| b.alloc_group == group)
+        .collect();
+    bridges.sort_by(|a, b| {
+        a.status
+            .cmp(&b.status)
+            .then(a.change_time.cmp(&b.change_time))
+    });
+    Ok(Json(bridges))
+}
+
+async fn group_stats(
+    Path(group): Path<String>,
+) -> Result<Json<BTreeMap<String, ProviderStats>>, AdminError> {
+    if group_config(&group).is_none() {
+        return Err(AdminError(
+            StatusCode::NOT_FOUND,
+            format!("no group {group}"),
+        ));
+    }
+    Ok(Json(analytics::group_stats(&group).await?))
+}
+
+async fn find_bridge(bridge_id: &str) -> Result<BridgeInfo, AdminError> {
+    DATABASE
+        .all_bridges()
+        .await?
+        .into_iter()
+        .find(|b| b.bridge_id == bridge_id)
+        .ok_or_else(|| AdminError(StatusCode::NOT_FOUND, format!("no bridge {bridge_id}")))
+}
+
+#[derive(Deserialize)]
+struct ForceStatus {
+    status: String,
+}
+
+async fn force_status(
+    Path(bridge_id): Path<String>,
+    Json(req): Json<ForceStatus>,
+) -> Result<Json<BridgeInfo>, AdminError> {
+    if !FORCEABLE_STATUSES.contains(&req.status.as_str()) {
+        return Err(AdminError(
+            StatusCode::BAD_REQUEST,
+            format!("status must be one of {}", FORCEABLE_STATUSES.join(", ")),
+        ));
+    }
+    find_bridge(&bridge_id).await?;
+    log::info!("admin API forces {bridge_id} into {}", req.status);
+    DATABASE.set_status(&bridge_id, &req.status).await?;
+    Ok(Json(find_bridge(&bridge_id).await?))
+}
+
+/// Forgets a bridge right away, so that nothing uses it any more and the next provisioning sweep destroys the server.
+async fn retire(Path(bridge_id): Path<String>) -> Result<StatusCode, AdminError> {
+    find_bridge(&bridge_id).await?;
+    log::info!("admin API retires {bridge_id}");
+    DATABASE.delete_bridge(&bridge_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn render_metrics() -> Result<String, AdminError> {
+    Ok(metrics::render()?)
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::database::{Lifecycle, DATABASE};
+
+/// How the bridges that one provider created for a group have fared.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ProviderStats {
+    pub created: usize,
+    /// How many bridges reached the frontline.
+    pub frontlined: usize,
+    /// How many bridges were blocked, whether in the frontline or in reserve.
+    pub blocked: usize,
+    /// The share of frontline bridges that were then blocked.
+    pub block_rate: Option<f64>,
+    /// The median wait in reserve before entering the frontline.
+    pub median_reserve_hr: Option<f64>,
+    /// The median time from entering the frontline to being blocked, counting bridges that were never blocked for as long as they lived.
+    pub median_survival_hr: Option<f64>,
+    /// Whether too few bridges were blocked to know the median survival, which is then the longest survival seen so far.
+    pub survival_is_lower_bound: bool,
+}
+
+/// The stats of every provider that ever created a bridge for the group.
+pub async fn group_stats(group: &str) -> anyhow::Result<BTreeMap<String, ProviderStats>> {
+    let lifecycles = DATABASE.lifecycles(group).await?;
+    Ok(summarize(&lifecycles, chrono::Utc::now().naive_utc()))
+}
+
+/// Works out the stats of every provider in the lifecycles, as of `now`.
+pub fn summarize(lifecycles: &[Lifecycle], now: NaiveDateTime) -> BTreeMap<String, ProviderStats> {
+    let mut by_provider: BTreeMap<&str, Vec<&Lifecycle>> = BTreeMap::new();
+    for lifecycle in lifecycles {
+        by_provider
+            .entry(&lifecycle.provider)
+            .or_default()
+            .push(lifecycle);
+    }
+    by_provider
+        .into_iter()
+        .map(|(provider, lifecycles)| (provider.to_string(), provider_stats(&lifecycles, now)))
+        .collect()
+}
+
+fn provider_stats(lifecycles: &[&Lifecycle], now: NaiveDateTime) -> ProviderStats {
+    let mut reserve_waits = vec![];
+    // how long each frontline bridge survived, and whether it ended by being blocked
+    let mut survivals = vec![];
+    for l in lifecycles {
+        let Some(frontline_at) = l.frontline_at else {
+            continue;
+        };
+        if let Some(reserve_at) = l.reserve_at {
+            reserve_waits.push(hours(frontline_at - reserve_at));
+        }
+        match l.blocked_at.filter(|b| *b >= frontline_at) {
+            Some(blocked_at) => survivals.push((hours(blocked_at - frontline_at), true)),
+            None => survivals.push((hours(l.ended_at.unwrap_or(now) - frontline_at), false)),
+        }
+    }
+    let frontline_blocks = survivals.iter().filter(|(_, blocked)| *blocked).count();
+    let median_survival = kaplan_meier_median(&mut survivals);
+    let longest = survivals.iter().map(|(hr, _)| *hr).reduce(f64::max);
+    ProviderStats {
+        created: lifecycles.len(),
+        frontlined: survivals.len(),
+        blocked: lifecycles.iter().filter(|l| l.blocked_at.is_some()).count(),
+        block_rate: (!survivals.is_empty())
+            .then(|| frontline_blocks as f64 / survivals.len() as f64),
+        median_reserve_hr: median(&mut reserve_waits),
+        median_survival_hr: median_survival.or(longest),
+        survival_is_lower_bound: median_survival.is_none() && longest.is_some(),
+    }
+}
+
+/// The median survival time, counting the survivors that were not blocked as surviving at least as long as they were seen, or None if fewer than half the bridges were ever blocked.
+fn kaplan_meier_median(survivals: &mut [(f64, bool)]) -> Option<f64> {
+    survivals.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut surviving = 1.0;
+    for (i, (hr, blocked)) in survivals.iter().enumerate() {
+        if *blocked {
+            surviving *= 1.0 - 1.0 / (survivals.len() - i) as f64;
+            if surviving <= 0.5 {
+                return Some(*hr);
+            }
+        }
+    }
+    None
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    values.sort_by(f64::total_cmp);
+    match values.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(values[n / 2]),
+        n => Some((values[n / 2 - 1] + values[n / 2]) / 2.0),
+    }
+}
+
+fn hours(duration: chrono::Duration) -> f64 {
+    duration.num_seconds() as f64 / 3600.0
+}
@@ -0,0 +1,184 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use argh::FromArgs;
+use once_cell::sync::Lazy;
+
+use crate::{
+    analytics::group_stats,
+    config::{GroupConfig, CONFIG},
+    database::DATABASE,
+    group_provider,
+    loop_provision::{create_bridges, drain, sweep_legacy_orphans, sweep_orphans},
+};
+
+/// Keeps groups of Geph bridges provisioned across cloud providers.
+#[derive(FromArgs)]
+pub struct Args {
+    /// the YAML configuration file
+    #[argh(positional)]
+    pub config: PathBuf,
+
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// The command line, parsed once.
+pub static ARGS: Lazy<Args> = Lazy::new(argh::from_env);
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum Command {
+    Run(Run),
+    ValidateConfig(ValidateConfig),
+    Status(Status),
+    Drain(Drain),
+    Retire(Retire),
+    Provision(Provision),
+    SweepOrphans(SweepOrphans),
+    Stats(Stats),
+}
+
+/// Run the daemon, which is also what happens without a command.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "run")]
+pub struct Run {}
+
+/// Check that the configuration file parses, then exit.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "validate-config")]
+pub struct ValidateConfig {}
+
+/// Print how many bridges every group has in every status.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "status")]
+pub struct Status {}
+
+/// Forget every bridge in a group, so that the next sweep destroys their servers. A running daemon refills the group unless its config asks for no bridges.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "drain")]
+pub struct Drain {
+    /// the group to drain
+    #[argh(positional)]
+    group: String,
+}
+
+/// Forget a single bridge, so that the next sweep destroys its server.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "retire")]
+pub struct Retire {
+    /// the bridge to retire
+    #[argh(positional)]
+    bridge_id: String,
+}
+
+/// Create and install new bridges in a group right away, adding them to its reserve.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "provision")]
+pub struct Provision {
+    /// the group to add bridges to
+    #[argh(positional)]
+    group: String,
+    /// how many bridges to create
+    #[argh(option, default = "1")]
+    count: usize,
+}
+
+/// Destroy the servers of a group's providers that the database no longer knows about.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "sweep-orphans")]
+pub struct SweepOrphans {
+    /// the group whose providers to sweep
+    #[argh(positional)]
+    group: String,
+    /// also destroy the untagged servers that versions before owner tags created on Hetzner, Scaleway, Linode, ServerSpace, OneProvider, OpenStack and OVH, recognized by names that look like bridge IDs. Only for accounts that phalanx has to itself
+    #[argh(switch)]
+    legacy: bool,
+}
+
+/// Print how long the bridges of every provider in a group survive, and how often they get blocked.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "stats")]
+pub struct Stats {
+    /// the group to report on
+    #[argh(positional)]
+    group: String,
+}
+
+/// Runs a one-off command, rather than the daemon.
+pub async fn run_command(command: &Command) -> anyhow::Result<()> {
+    match command {
+        Command::Run(_) => unreachable!("the daemon is not a one-off command"),
+        Command::ValidateConfig(_) => {
+            // by now, the config has already been validated
+            println!(
+                "{} is valid, with {} groups",
+                ARGS.config.display(),
+                CONFIG.groups.len()
+            );
+        }
+        Command::Status(_) => {
+            for group in CONFIG.groups.keys() {
+                let counts = DATABASE.status_counts(group).await?;
+                let counts: Vec<_> = counts
+                    .iter()
+                    .map(|(status, count)| format!("{status}={count}"))
+                    .collect();
+                println!("{group}: {}", counts.join(" "));
+            }
+        }
+        Command::Drain(Drain { group }) => {
+            check_group(group)?;
+            let drained = drain(group).await?;
+            println!("drained {drained} bridges from {group}");
+        }
+        Command::Retire(Retire { bridge_id }) => {
+            DATABASE
+                .all_bridges()
+                .await?
+                .iter()
+                .find(|b| &b.bridge_id == bridge_id)
+                .with_context(|| format!("no bridge {bridge_id}"))?;
+            DATABASE.delete_bridge(bridge_id).await?;
+            println!("retired {bridge_id}");
+        }
+        Command::Provision(Provision { group, count }) => {
+            let cfg = check_group(group)?;
+            let provider = group_provider(group, cfg);
+            let created = create_bridges(group, cfg, provider.as_ref(), *count).await;
+            println!("added {created} of {count} bridges to {group}");
+        }
+        Command::SweepOrphans(SweepOrphans { group, legacy }) => {
+            let cfg = check_group(group)?;
+            let provider = group_provider(group, cfg);
+            sweep_orphans(provider.as_ref()).await?;
+            if *legacy {
+                sweep_legacy_orphans(provider.as_ref()).await?;
+            }
+        }
+        Command::Stats(Stats { group }) => {
+            check_group(group)?;
+            for (provider, stats) in group_stats(group).await? {
+                let hours = |hr: Option<f64>| hr.map_or("-".into(), |hr| format!("{hr:.1}h"));
+                println!(
+                    "{provider}: created={} frontlined={} blocked={} block_rate={} median_reserve={} median_survival={}{}",
+                    stats.created,
+                    stats.frontlined,
+                    stats.blocked,
+                    stats.block_rate.map_or("-".into(), |r| format!("{:.0}%", r * 100.0)),
+                    hours(stats.median_reserve_hr),
+                    if stats.survival_is_lower_bound { ">" } else { "" },
+                    hours(stats.median_survival_hr),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_group(group: &str) -> anyhow::Result<&'static GroupConfig> {
+    CONFIG
+        .groups
+        .get(group)
+        .with_context(|| format!("no group {group}"))
+}
@@ -1,23 +1,127 @@
-use std::collections::BTreeMap;
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    path::Path,
+};
+
+use anyhow::Context;
 
 use once_cell::sync::Lazy;
+use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 
 use crate::provider::{
-    hetzner::HetznerConfig, lightsail::LightsailConfig, linode::LinodeConfig,
-    oneprovider::OneCloudConfig, ovh::OvhConfig, scaleway::ScalewayConfig,
+    digitalocean::DigitalOceanConfig, ec2::Ec2Config, hetzner::HetznerConfig,
+    lightsail::LightsailConfig, linode::LinodeConfig, oneprovider::OneCloudConfig,
+    openstack::OpenStackConfig, ovh::OvhConfig, scaleway::ScalewayConfig,
     serverspace::ServerSpaceConfig, vultr::VultrConfig,
 };
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 /// YAML configuration file
 pub struct Config {
-    /// The URL of the main postgres database.
-    pub postgres_url: String,
+    /// The URL of the main database: Postgres in production, or `sqlite:` for the embedded backend.
+    #[serde(alias = "postgres_url")]
+    pub database_url: String,
     /// The bridge secret.
     pub bridge_secret: String,
     /// Bridge groups
     pub groups: BTreeMap<String, GroupConfig>,
+    /// The admin HTTP API, which is off unless configured.
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    /// Only print what every loop would do, without touching any server or writing to the database.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// How bridges are probed for GFW blocking.
+    #[serde(default)]
+    pub gfw: GfwConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+/// Configuration for GFW block detection
+pub struct GfwConfig {
+    /// Ping an address in China from the bridge.
+    pub ping: bool,
+    /// Servers in China, as `host:port`, that the bridge tries to connect to.
+    pub tcp_targets: Vec<String>,
+    /// Resolvers in China that the bridge asks for `dns_domain`.
+    pub dns_resolvers: Vec<String>,
+    /// A name that resolves to the address it spells out, with `{ip}` standing for the bridge's, so that a poisoned answer gives itself away.
+    pub dns_domain: String,
+    /// Agents inside China that report whether they can reach a bridge.
+    pub vantage_agents: Vec<String>,
+    /// How many probes must agree for a round of probing to find a bridge blocked, or reachable.
+    pub quorum: usize,
+    /// How many of the latest rounds decide a bridge's status.
+    pub rounds: usize,
+    /// A bridge is blocked once this many of those rounds found it blocked...
+    pub block_after: usize,
+    /// ...and unblocked once this many found it reachable.
+    pub unblock_after: usize,
+    /// Rounds older than this are ignored, and soon forgotten. Blocked bridges are kept this long before they are deleted, in case they get unblocked.
+    pub window_mins: u64,
+}
+
+impl Default for GfwConfig {
+    fn default() -> Self {
+        Self {
+            ping: true,
+            tcp_targets: vec!["www.baidu.com:443".into(), "www.qq.com:443".into()],
+            dns_resolvers: vec!["114.114.114.114".into(), "223.5.5.5".into()],
+            dns_domain: "{ip}.sslip.io".into(),
+            vantage_agents: vec![],
+            quorum: 2,
+            rounds: 5,
+            block_after: 3,
+            unblock_after: 5,
+            window_mins: 60,
+        }
+    }
+}
+
+impl GfwConfig {
+    /// How many probes are enabled.
+    pub fn probe_count(&self) -> usize {
+        self.ping as usize
+            + !self.tcp_targets.is_empty() as usize
+            + !self.dns_resolvers.is_empty() as usize
+            + self.vantage_agents.len()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+/// Configuration for rejecting servers whose IPs are likely burned
+pub struct IpFreshness {
+    /// The most servers to create, and reject, before giving up on getting a fresh IP.
+    pub max_attempts: usize,
+    /// Also reject IPv4 addresses in a subnet of this prefix length, such as 24, where a bridge was recently blocked. IPv6 addresses are never matched by subnet, only by the exact address.
+    pub block_prefix: Option<u8>,
+    /// How long a blocked bridge taints its subnet.
+    pub block_memory_days: u64,
+}
+
+impl Default for IpFreshness {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            block_prefix: None,
+            block_memory_days: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Configuration for the admin HTTP API
+pub struct AdminConfig {
+    /// Where to listen, e.g. `127.0.0.1:8080`.
+    pub listen: SocketAddr,
+    /// The bearer token every request must carry, except for scrapes of `/metrics`.
+    pub token: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -31,7 +135,12 @@ pub struct GroupConfig {
     pub override_group: Option<String>,
     #[serde(default)]
     pub no_antigfw: bool,
-    pub provider: ProviderConfig,
+    /// The provider, for groups that only use one.
+    #[serde(default)]
+    pub provider: Option<ProviderConfig>,
+    /// The providers, for groups that spread over several.
+    #[serde(default)]
+    pub providers: Vec<ProviderChoice>,
     /// Maximum lifetime.
     pub avg_lifetime_hr: f64,
     pub services: Vec<Service>,
@@ -51,6 +160,102 @@ pub struct GroupConfig {
     /// Override the total rate limit for Geph5Exit nodes (in Mbps)
     #[serde(default)]
     pub exit_total_ratelimit: Option<u64>,
+
+    /// How hard the provider may be asked for new servers
+    #[serde(default)]
+    pub create_limit: CreateLimit,
+
+    /// Reject servers whose IPs are not fresh, on every provider. Unset, only Linode does
+    #[serde(default)]
+    pub fresh_ips: Option<bool>,
+
+    /// How fresh the IPs must be, for the providers that reject stale ones
+    #[serde(default)]
+    pub ip_freshness: IpFreshness,
+
+    /// Weight new creations toward the providers whose bridges survive longest in the frontline
+    #[serde(default)]
+    pub weight_by_survival: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+/// Limits on server creation, so that a provider that rejects creates does not get flooded
+pub struct CreateLimit {
+    /// Creates per minute, shared by every group on the same provider account, so those groups must all agree on it and on the burst.
+    pub per_minute: f64,
+    /// How many creates may go out back to back.
+    pub burst: u32,
+    /// The longest wait between attempts after consecutive failures.
+    pub max_backoff_secs: u64,
+    /// Consecutive failures after which the provider is marked unhealthy.
+    pub breaker_threshold: u32,
+    /// How long an unhealthy provider is left alone before trying it again.
+    pub breaker_cooldown_secs: u64,
+}
+
+impl Default for CreateLimit {
+    fn default() -> Self {
+        Self {
+            per_minute: 6.0,
+            burst: 5,
+            max_backoff_secs: 600,
+            breaker_threshold: 8,
+            breaker_cooldown_secs: 1800,
+        }
+    }
+}
+
+impl GroupConfig {
+    /// Whether servers from this provider must have fresh IPs.
+    pub fn wants_fresh_ips(&self, provider: &ProviderConfig) -> bool {
+        self.fresh_ips
+            .unwrap_or(matches!(provider, ProviderConfig::Linode(_)))
+    }
+
+    /// Every provider of the group, named by type, or by type and position if the group uses a type more than once.
+    pub fn provider_choices(&self) -> Vec<(String, ProviderChoice)> {
+        let choices: Vec<ProviderChoice> = self
+            .provider
+            .iter()
+            .map(|provider| ProviderChoice {
+                provider: provider.clone(),
+                weight: 1.0,
+                priority: 0,
+            })
+            .chain(self.providers.iter().cloned())
+            .collect();
+        choices
+            .iter()
+            .enumerate()
+            .map(|(i, choice)| {
+                let kind = choice.provider.kind();
+                let label = if choices.iter().filter(|c| c.provider.kind() == kind).count() > 1 {
+                    format!("{kind}-{i}")
+                } else {
+                    kind.to_string()
+                };
+                (label, choice.clone())
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+/// One of several providers of a group
+pub struct ProviderChoice {
+    #[serde(flatten)]
+    pub provider: ProviderConfig,
+    /// How much of the group this provider gets, relative to the others of the same priority.
+    #[serde(default = "one")]
+    pub weight: f64,
+    /// Providers with a lower priority are preferred, and the rest are only used while those fail.
+    #[serde(default)]
+    pub priority: u32,
+}
+
+fn one() -> f64 {
+    1.0
 }
 
 fn huge_mbps() -> f64 {
@@ -66,6 +271,18 @@ pub enum Service {
     Geph5Exit,
 }
 
+impl Service {
+    /// The systemd unit the service's deployment script installs.
+    pub fn systemd_unit(&self) -> &'static str {
+        match self {
+            Service::Geph4 => "geph4-bridge",
+            Service::Geph5 => "geph5-bridge",
+            Service::Earendil => "earendil",
+            Service::Geph5Exit => "geph5-exit",
+        }
+    }
+}
+
 pub const GEPH4_GIST: &str = "https://gist.githubusercontent.com/nullchinchilla/746ec2007cc293af881f7354405cfb6e/raw/deploy-bridge-geph4.sh";
 pub const GEPH5_GIST: &str = "https://gist.githubusercontent.com/nullchinchilla/64a3ded0b62f1decef65c84f43e45dbe/raw/deploy-bridge-geph5.sh";
 pub const EARENDIL_GIST: &str = "https://gist.githubusercontent.com/nullchinchilla/26ccd7af71f403df1495e4038a6ce9ff/raw/deploy-bridge-earendil.sh";
@@ -85,11 +302,255 @@ pub enum ProviderConfig {
     Onecloud(OneCloudConfig),
     Linode(LinodeConfig),
     ServerSpace(ServerSpaceConfig),
+    DigitalOcean(DigitalOceanConfig),
+    Ec2(Ec2Config),
+    Openstack(OpenStackConfig),
+}
+
+impl ProviderConfig {
+    /// The name of the provider, as written in the `type` field.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProviderConfig::Lightsail(_) => "lightsail",
+            ProviderConfig::Vultr(_) => "vultr",
+            ProviderConfig::Scaleway(_) => "scaleway",
+            ProviderConfig::Hetzner(_) => "hetzner",
+            ProviderConfig::Ovh(_) => "ovh",
+            ProviderConfig::Onecloud(_) => "onecloud",
+            ProviderConfig::Linode(_) => "linode",
+            ProviderConfig::ServerSpace(_) => "server_space",
+            ProviderConfig::DigitalOcean(_) => "digital_ocean",
+            ProviderConfig::Ec2(_) => "ec2",
+            ProviderConfig::Openstack(_) => "openstack",
+        }
+    }
+
+    /// Identifies the account the provider acts on, without revealing its credentials.
+    pub fn account(&self) -> String {
+        let credentials = match self {
+            ProviderConfig::Lightsail(cfg) => cfg.access_key_id.clone(),
+            ProviderConfig::Vultr(cfg) => cfg.api_key.clone(),
+            ProviderConfig::Scaleway(cfg) => cfg.secret_key.clone(),
+            ProviderConfig::Hetzner(cfg) => cfg.api_token.clone(),
+            // the same OpenStack account can be used in several regions
+            ProviderConfig::Ovh(cfg) => format!(
+                "{:?}",
+                cfg.env_variables
+                    .iter()
+                    .filter(|(k, _)| !k.contains("REGION"))
+                    .collect::<Vec<_>>()
+            ),
+            ProviderConfig::Onecloud(cfg) => cfg.api_key.clone(),
+            ProviderConfig::Linode(cfg) => cfg.api_token.clone(),
+            ProviderConfig::ServerSpace(cfg) => cfg.api_key.clone(),
+            ProviderConfig::DigitalOcean(cfg) => cfg.api_token.clone(),
+            ProviderConfig::Ec2(cfg) => cfg.access_key_id.clone(),
+            ProviderConfig::Openstack(cfg) => {
+                format!("{} {} {}", cfg.auth_url, cfg.project, cfg.username)
+            }
+        };
+        let mut hasher = DefaultHasher::new();
+        credentials.hash(&mut hasher);
+        format!("{}:{:016x}", self.kind(), hasher.finish())
+    }
 }
 
 /// Global configuration file
-pub static CONFIG: Lazy<Config> = Lazy::new(|| {
-    let bts = std::fs::read(&std::env::args().collect::<Vec<_>>()[1]).unwrap();
+#[cfg(not(test))]
+pub static CONFIG: Lazy<Config> =
+    Lazy::new(|| load_config(&crate::cli::ARGS.config).expect("could not load the config"));
+
+/// The group configs as last loaded. Unlike the rest of [CONFIG], these change when the config file is reloaded.
+static GROUPS: Lazy<RwLock<BTreeMap<String, GroupConfig>>> =
+    Lazy::new(|| RwLock::new(CONFIG.groups.clone()));
+
+/// The current config of a group, or None if the group has been removed.
+pub fn group_config(group: &str) -> Option<GroupConfig> {
+    GROUPS.read().get(group).cloned()
+}
+
+/// The current configs of every group.
+pub fn group_configs() -> BTreeMap<String, GroupConfig> {
+    GROUPS.read().clone()
+}
+
+/// Swaps in freshly loaded group configs, returning the ones they replace.
+pub fn replace_group_configs(
+    groups: BTreeMap<String, GroupConfig>,
+) -> BTreeMap<String, GroupConfig> {
+    std::mem::replace(&mut GROUPS.write(), groups)
+}
+
+/// Reads, parses and validates a configuration file, reporting every problem along with where in the YAML it is.
+pub fn load_config(path: &Path) -> anyhow::Result<Config> {
+    let bts = std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+    // serde_yaml errors already say where in the YAML they are
+    let config: Config = serde_yaml::from_slice(&bts)
+        .map_err(|err| anyhow::anyhow!("invalid {}: {err}", path.display()))?;
+
+    let mut problems = vec![];
+    // serde cannot deny unknown fields through the flattened provider configs, so compare against what it understood instead
+    let written: serde_yaml::Value = serde_yaml::from_slice(&bts)?;
+    let understood = serde_yaml::to_value(&config)?;
+    unknown_fields(&written, &understood, "", &mut problems);
+    config.check(&mut problems);
+    if !problems.is_empty() {
+        anyhow::bail!("invalid {}:\n  {}", path.display(), problems.join("\n  "));
+    }
+    Ok(config)
+}
+
+/// Reports the keys that were written but that no field took.
+fn unknown_fields(
+    written: &serde_yaml::Value,
+    understood: &serde_yaml::Value,
+    path: &str,
+    problems: &mut Vec<String>,
+) {
+    use serde_yaml::Value;
+    match (written, understood) {
+        (Value::Mapping(written), Value::Mapping(understood)) => {
+            for (key, value) in written {
+                let key = key.as_str().unwrap_or("?");
+                let key_path = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{path}.{key}")
+                };
+                match understood.get(key) {
+                    Some(understood) => unknown_fields(value, understood, &key_path, problems),
+                    None if path.is_empty() && key == "postgres_url" => {}
+                    None => problems.push(format!("{key_path}: unknown field")),
+                }
+            }
+        }
+        (Value::Sequence(written), Value::Sequence(understood)) => {
+            for (i, (value, understood)) in written.iter().zip(understood).enumerate() {
+                unknown_fields(value, understood, &format!("{path}[{i}]"), problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Config {
+    /// Reports the problems that parsing alone does not catch.
+    fn check(&self, problems: &mut Vec<String>) {
+        let probes = self.gfw.probe_count();
+        if self.gfw.quorum == 0 || self.gfw.quorum > probes {
+            problems.push(format!(
+                "gfw.quorum: must be between 1 and the {probes} probes enabled"
+            ));
+        }
+        let rounds = self.gfw.rounds;
+        if rounds == 0 {
+            problems.push("gfw.rounds: must be positive".into());
+        }
+        if self.gfw.block_after == 0 || self.gfw.block_after > rounds {
+            problems.push(format!(
+                "gfw.block_after: must be between 1 and the {rounds} rounds"
+            ));
+        }
+        if self.gfw.unblock_after == 0 || self.gfw.unblock_after > rounds {
+            problems.push(format!(
+                "gfw.unblock_after: must be between 1 and the {rounds} rounds"
+            ));
+        }
+        if self.gfw.window_mins == 0 {
+            problems.push("gfw.window_mins: must be positive".into());
+        }
+        if !self.gfw.dns_resolvers.is_empty() && !self.gfw.dns_domain.contains("{ip}") {
+            problems
+                .push("gfw.dns_domain: must contain {ip}, to tell poisoned answers apart".into());
+        }
+        for target in self.gfw.tcp_targets.iter() {
+            if target.rsplit_once(':').is_none() {
+                problems.push(format!("gfw.tcp_targets: {target} is not host:port"));
+            }
+        }
+        // the groups that set the create limit of each account so far
+        let mut limit_setters: BTreeMap<String, (&str, &CreateLimit)> = BTreeMap::new();
+        for (name, group) in self.groups.iter() {
+            let mut problem = |field: &str, what: String| {
+                problems.push(format!("groups.{name}.{field}: {what}"));
+            };
+            for (_, choice) in group.provider_choices() {
+                let limit = &group.create_limit;
+                match limit_setters.get(&choice.provider.account()) {
+                    Some((other, other_limit))
+                        if other_limit.per_minute != limit.per_minute
+                            || other_limit.burst != limit.burst =>
+                    {
+                        problem(
+                            "create_limit",
+                            format!(
+                                "differs from the one of {other}, on the same provider account"
+                            ),
+                        );
+                        break;
+                    }
+                    Some(_) => {}
+                    None => {
+                        limit_setters.insert(choice.provider.account(), (name, limit));
+                    }
+                }
+            }
+            if group.max_frontline.is_some_and(|max| max < group.frontline) {
+                problem(
+                    "max_frontline",
+                    format!("is below the frontline of {}", group.frontline),
+                );
+            }
+            if group.frontline > 0 && group.reserve == 0 {
+                problem(
+                    "reserve",
+                    "must be above 0, since the frontline is filled from the reserve".into(),
+                );
+            }
+            for (field, value) in [
+                ("avg_lifetime_hr", group.avg_lifetime_hr),
+                ("target_mbps", group.target_mbps),
+                ("create_limit.per_minute", group.create_limit.per_minute),
+            ] {
+                if value.partial_cmp(&0.0) != Some(Ordering::Greater) {
+                    problem(field, "must be positive".into());
+                }
+            }
+            if let Some(other) = &group.override_group {
+                if !self.groups.contains_key(other) {
+                    problem("override_group", format!("there is no group {other}"));
+                }
+            }
+            if group.services.is_empty() {
+                problem("services", "must not be empty".into());
+            }
+            if group.ip_freshness.max_attempts == 0 {
+                problem("ip_freshness.max_attempts", "must be positive".into());
+            }
+            if group.ip_freshness.block_prefix.is_some_and(|p| p > 32) {
+                problem(
+                    "ip_freshness.block_prefix",
+                    "must be an IPv4 prefix length, up to 32".into(),
+                );
+            }
+            if !group.services.contains(&Service::Geph5Exit) {
+                for (field, set) in [
+                    ("exit_country", group.exit_country.is_some()),
+                    ("exit_city", group.exit_city.is_some()),
+                    ("exit_total_ratelimit", group.exit_total_ratelimit.is_some()),
+                ] {
+                    if set {
+                        problem(field, "only applies to the geph5_exit service".into());
+                    }
+                }
+            }
+            if group.provider_choices().is_empty() {
+                problem("providers", "the group has no provider".into());
+            }
+        }
+    }
+}
 
-    serde_yaml::from_slice(&bts).unwrap()
-});
+/// Under test, the loops run against the harness configuration instead.
+#[cfg(test)]
+pub static CONFIG: Lazy<Config> = Lazy::new(crate::harness::config);
@@ -1,23 +1,38 @@
-use std::time::Duration;
+pub mod postgres;
+pub mod sqlite;
 
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
 use chrono::NaiveDateTime;
 use once_cell::sync::Lazy;
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-
-use crate::config::CONFIG;
-
-/// The global database instance.
-pub static DATABASE: Lazy<Pool<Postgres>> = Lazy::new(|| {
-    PgPoolOptions::new()
-        .acquire_timeout(Duration::from_secs(15))
-        .idle_timeout(Some(Duration::from_secs(15)))
-        .max_connections(6)
-        .connect_lazy(&CONFIG.postgres_url)
-        .unwrap()
+use serde::Serialize;
+
+use crate::{config::CONFIG, plan::DryRunStore};
+
+use self::{postgres::PostgresStore, sqlite::SqliteStore};
+
+/// The global database instance. A `sqlite:` URL selects the embedded backend; anything else is treated as Postgres.
+pub static DATABASE: Lazy<Box<dyn BridgeStore>> = Lazy::new(|| {
+    let url = &CONFIG.database_url;
+    if url.starts_with("sqlite:") {
+        guard_dry_run(SqliteStore::new(url).unwrap())
+    } else {
+        guard_dry_run(PostgresStore::new(url).unwrap())
+    }
 });
 
+/// On a dry run, keeps the store from being written to.
+fn guard_dry_run<T: BridgeStore>(store: T) -> Box<dyn BridgeStore> {
+    if CONFIG.dry_run {
+        Box::new(DryRunStore::new(store))
+    } else {
+        Box::new(store)
+    }
+}
+
 /// Info about a particular bridge, stored in the database.
-#[derive(sqlx::FromRow, Clone)]
+#[derive(sqlx::FromRow, Serialize, Clone)]
 pub struct BridgeInfo {
     pub bridge_id: String,
     pub ip_addr: String,
@@ -26,3 +41,133 @@ pub struct BridgeInfo {
     pub change_time: NaiveDateTime,
     pub last_mbps: f64,
 }
+
+/// The result of one probe of a bridge, kept as evidence for whether it is blocked.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct ProbeRecord {
+    pub bridge_id: String,
+    /// The detector that probed, or `quorum` for the verdict of a whole round of probing.
+    pub probe: String,
+    pub verdict: String,
+    pub probe_time: NaiveDateTime,
+}
+
+/// When a bridge reached each stage of its life, for working out how long the bridges of every provider survive.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct Lifecycle {
+    pub bridge_id: String,
+    pub alloc_group: String,
+    /// The name of the provider that created the bridge, within its group.
+    pub provider: String,
+    pub created_at: NaiveDateTime,
+    pub reserve_at: Option<NaiveDateTime>,
+    pub frontline_at: Option<NaiveDateTime>,
+    pub blocked_at: Option<NaiveDateTime>,
+    /// When the bridge was deleted, for whatever reason.
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+/// The operations the loops perform on the bridge database.
+#[async_trait]
+pub trait BridgeStore: Send + Sync + 'static {
+    /// Lists every bridge, in every group.
+    async fn all_bridges(&self) -> anyhow::Result<Vec<BridgeInfo>>;
+
+    /// Lists the bridges in a group that have the given status.
+    async fn group_bridges(&self, group: &str, status: &str) -> anyhow::Result<Vec<BridgeInfo>>;
+
+    /// Lists the bridges in a group that have had the given status for at least so many seconds.
+    async fn stale_bridges(
+        &self,
+        group: &str,
+        status: &str,
+        older_than_secs: i64,
+    ) -> anyhow::Result<Vec<BridgeInfo>>;
+
+    /// Counts the bridges in a group, by status.
+    async fn status_counts(&self, group: &str) -> anyhow::Result<BTreeMap<String, i64>>;
+
+    /// Picks any reserve bridge in the group.
+    async fn pick_reserve(&self, group: &str) -> anyhow::Result<Option<BridgeInfo>>;
+
+    /// Inserts a freshly created bridge.
+    async fn insert_bridge(
+        &self,
+        bridge_id: &str,
+        ip_addr: &str,
+        group: &str,
+        status: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Moves a bridge to a new status, resetting its change time.
+    async fn set_status(&self, bridge_id: &str, status: &str) -> anyhow::Result<()>;
+
+    /// Records the throughput last measured on the bridge at the given address.
+    async fn set_last_mbps(&self, ip_addr: &str, mbps: f64) -> anyhow::Result<()>;
+
+    /// Deletes a single bridge.
+    async fn delete_bridge(&self, bridge_id: &str) -> anyhow::Result<()>;
+
+    /// Deletes the bridge in the group with the given status that changed status longest ago.
+    async fn delete_oldest(&self, group: &str, status: &str) -> anyhow::Result<()>;
+
+    /// Deletes every bridge that has had the given status for at least so many seconds, returning how many there were.
+    async fn delete_with_status(&self, status: &str, older_than_secs: i64) -> anyhow::Result<u64>;
+
+    /// Deletes the slowest bridge in the group, ignoring bridges that carry no real traffic. Returns how many were deleted, which can be more than one on a tie.
+    async fn delete_slowest(&self, group: &str) -> anyhow::Result<u64>;
+
+    /// Sets the artificial delay that clients of an overloaded group get.
+    async fn set_group_delay(&self, group: &str, delay_ms: i32) -> anyhow::Result<()>;
+
+    /// Records an IP address as seen, returning whether it had never been seen before.
+    async fn claim_seen_ip(&self, ip_addr: &str) -> anyhow::Result<bool>;
+
+    /// Notes that a bridge on this IP was blocked, which taints its subnet for a while.
+    async fn record_blocked_ip(&self, ip_addr: &str) -> anyhow::Result<()>;
+
+    /// Lists the IPs whose bridges were blocked within the last so many seconds.
+    async fn blocked_ips(&self, within_secs: i64) -> anyhow::Result<Vec<String>>;
+
+    /// Returns the SSH host key pinned for the server at the given address.
+    async fn host_key(&self, ip_addr: &str) -> anyhow::Result<Option<String>>;
+
+    /// Pins the SSH host key of the server at the given address, replacing any earlier one.
+    async fn set_host_key(&self, ip_addr: &str, host_key: &str) -> anyhow::Result<()>;
+
+    /// Records the result of probing a bridge.
+    async fn record_probe(&self, bridge_id: &str, probe: &str, verdict: &str)
+        -> anyhow::Result<()>;
+
+    /// Lists the probe results of a bridge from the last so many seconds, newest first.
+    async fn recent_probes(
+        &self,
+        bridge_id: &str,
+        window_secs: i64,
+    ) -> anyhow::Result<Vec<ProbeRecord>>;
+
+    /// Deletes the probe results older than so many seconds, returning how many there were.
+    async fn forget_probes(&self, older_than_secs: i64) -> anyhow::Result<u64>;
+
+    /// Keeps the evidence behind the decision to block a bridge, for good.
+    async fn record_block(
+        &self,
+        bridge_id: &str,
+        ip_addr: &str,
+        evidence: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Notes which provider created a bridge, which starts its lifecycle. Later stages are noted as the bridge changes status.
+    async fn record_origin(
+        &self,
+        bridge_id: &str,
+        group: &str,
+        provider: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Lists the lifecycles of every bridge ever created for a group.
+    async fn lifecycles(&self, group: &str) -> anyhow::Result<Vec<Lifecycle>>;
+
+    /// Creates the tables that phalanx itself introduced, if they don't exist yet.
+    async fn migrate(&self) -> anyhow::Result<()>;
+}
@@ -0,0 +1,366 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use async_trait::async_trait;
+use sqlx::{postgres::PgPoolOptions, Executor, Pool, Postgres};
+
+use super::{BridgeInfo, BridgeStore, Lifecycle, ProbeRecord};
+
+/// Tables that phalanx introduced on top of the shared schema.
+const MIGRATIONS: &str = r#"
+create table if not exists bridge_host_keys (
+    ip_addr text primary key,
+    host_key text not null
+);
+create table if not exists bridge_probe_history (
+    id bigserial primary key,
+    bridge_id text not null,
+    probe text not null,
+    verdict text not null,
+    probe_time timestamp not null
+);
+create index if not exists bridge_probe_history_bridge on bridge_probe_history (bridge_id, probe_time);
+create table if not exists bridge_block_evidence (
+    bridge_id text not null,
+    ip_addr text not null,
+    decided_at timestamp not null,
+    evidence text not null
+);
+create table if not exists bridge_lifecycles (
+    bridge_id text primary key,
+    alloc_group text not null,
+    provider text not null,
+    created_at timestamp not null,
+    reserve_at timestamp,
+    frontline_at timestamp,
+    blocked_at timestamp,
+    ended_at timestamp
+);
+alter table phalanx_seen_ips add column if not exists blocked_at timestamp;
+create index if not exists phalanx_seen_ips_blocked on phalanx_seen_ips (blocked_at);
+"#;
+
+/// The production backend, backed by the main Postgres database.
+pub struct PostgresStore {
+    pool: Pool<Postgres>,
+}
+
+impl PostgresStore {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_secs(15))
+            .idle_timeout(Some(Duration::from_secs(15)))
+            .max_connections(6)
+            .connect_lazy(url)?;
+        Ok(Self { pool })
+    }
+
+    /// Notes when a bridge first reached a status that the analytics care about.
+    async fn mark_stage(&self, bridge_id: &str, status: &str) -> anyhow::Result<()> {
+        let column = match status {
+            "reserve" => "reserve_at",
+            "frontline" => "frontline_at",
+            "blocked" => "blocked_at",
+            _ => return Ok(()),
+        };
+        sqlx::query(&format!(
+            "update bridge_lifecycles set {column} = coalesce({column}, NOW()) where bridge_id = $1"
+        ))
+        .bind(bridge_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Notes the end of the bridges that were just deleted, given the rows the delete returned.
+    async fn end_lifecycles(&self, deleted: Vec<(String,)>) -> anyhow::Result<u64> {
+        if deleted.is_empty() {
+            return Ok(0);
+        }
+        let ids: Vec<String> = deleted.into_iter().map(|row| row.0).collect();
+        sqlx::query(
+            "update bridge_lifecycles set ended_at = NOW() where ended_at is null and bridge_id = any($1)",
+        )
+        .bind(&ids)
+        .execute(&self.pool)
+        .await?;
+        Ok(ids.len() as u64)
+    }
+}
+
+#[async_trait]
+impl BridgeStore for PostgresStore {
+    async fn all_bridges(&self) -> anyhow::Result<Vec<BridgeInfo>> {
+        Ok(sqlx::query_as("select * from bridges")
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn group_bridges(&self, group: &str, status: &str) -> anyhow::Result<Vec<BridgeInfo>> {
+        Ok(
+            sqlx::query_as("select * from bridges where alloc_group = $1 and status = $2")
+                .bind(group)
+                .bind(status)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn stale_bridges(
+        &self,
+        group: &str,
+        status: &str,
+        older_than_secs: i64,
+    ) -> anyhow::Result<Vec<BridgeInfo>> {
+        Ok(sqlx::query_as(
+            "select * from bridges where alloc_group = $1 and status = $2 and change_time < NOW() - make_interval(secs => $3)",
+        )
+        .bind(group)
+        .bind(status)
+        .bind(older_than_secs as f64)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn status_counts(&self, group: &str) -> anyhow::Result<BTreeMap<String, i64>> {
+        let counts: Vec<(String, i64)> = sqlx::query_as(
+            "select status, count(bridge_id) from bridges where alloc_group = $1 group by status",
+        )
+        .bind(group)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(counts.into_iter().collect())
+    }
+
+    async fn pick_reserve(&self, group: &str) -> anyhow::Result<Option<BridgeInfo>> {
+        Ok(sqlx::query_as(
+            "select * from bridges where status = 'reserve' and alloc_group = $1 limit 1",
+        )
+        .bind(group)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn insert_bridge(
+        &self,
+        bridge_id: &str,
+        ip_addr: &str,
+        group: &str,
+        status: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query("insert into bridges (bridge_id, ip_addr, alloc_group, status, change_time) values ($1, $2, $3, $4, NOW())")
+            .bind(bridge_id)
+            .bind(ip_addr)
+            .bind(group)
+            .bind(status)
+            .execute(&self.pool)
+            .await?;
+        self.mark_stage(bridge_id, status).await
+    }
+
+    async fn set_status(&self, bridge_id: &str, status: &str) -> anyhow::Result<()> {
+        sqlx::query("update bridges set status = $1, change_time = NOW() where bridge_id = $2")
+            .bind(status)
+            .bind(bridge_id)
+            .execute(&self.pool)
+            .await?;
+        self.mark_stage(bridge_id, status).await
+    }
+
+    async fn set_last_mbps(&self, ip_addr: &str, mbps: f64) -> anyhow::Result<()> {
+        sqlx::query("update bridges set last_mbps = $1 where ip_addr = $2")
+            .bind(mbps)
+            .bind(ip_addr)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_bridge(&self, bridge_id: &str) -> anyhow::Result<()> {
+        let deleted =
+            sqlx::query_as("delete from bridges where bridge_id = $1 returning bridge_id")
+                .bind(bridge_id)
+                .fetch_all(&self.pool)
+                .await?;
+        self.end_lifecycles(deleted).await?;
+        Ok(())
+    }
+
+    async fn delete_oldest(&self, group: &str, status: &str) -> anyhow::Result<()> {
+        let deleted = sqlx::query_as("delete from bridges where bridge_id in (select bridge_id from bridges where status = $1 and alloc_group = $2 order by change_time limit 1) returning bridge_id")
+            .bind(status)
+            .bind(group)
+            .fetch_all(&self.pool)
+            .await?;
+        self.end_lifecycles(deleted).await?;
+        Ok(())
+    }
+
+    async fn delete_with_status(&self, status: &str, older_than_secs: i64) -> anyhow::Result<u64> {
+        let deleted = sqlx::query_as("delete from bridges where status = $1 and change_time <= NOW() - make_interval(secs => $2) returning bridge_id")
+            .bind(status)
+            .bind(older_than_secs as f64)
+            .fetch_all(&self.pool)
+            .await?;
+        self.end_lifecycles(deleted).await
+    }
+
+    async fn delete_slowest(&self, group: &str) -> anyhow::Result<u64> {
+        let deleted = sqlx::query_as(
+            "delete from bridges where alloc_group = $1 and last_mbps = (
+        SELECT MIN(last_mbps)
+        FROM bridges
+        WHERE alloc_group = $1
+        AND last_mbps > 1
+      ) returning bridge_id",
+        )
+        .bind(group)
+        .fetch_all(&self.pool)
+        .await?;
+        self.end_lifecycles(deleted).await
+    }
+
+    async fn set_group_delay(&self, group: &str, delay_ms: i32) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO bridge_group_delays (pool, delay_ms, is_plus)
+VALUES ($1, $2, false)
+ON CONFLICT (pool)
+DO
+UPDATE SET 
+delay_ms = EXCLUDED.delay_ms"#,
+        )
+        .bind(group)
+        .bind(delay_ms)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn claim_seen_ip(&self, ip_addr: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO phalanx_seen_ips (ip_addr) VALUES ($1) ON CONFLICT DO NOTHING",
+        )
+        .bind(ip_addr)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn record_blocked_ip(&self, ip_addr: &str) -> anyhow::Result<()> {
+        sqlx::query("insert into phalanx_seen_ips (ip_addr, blocked_at) values ($1, NOW()) on conflict (ip_addr) do update set blocked_at = excluded.blocked_at")
+            .bind(ip_addr)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn blocked_ips(&self, within_secs: i64) -> anyhow::Result<Vec<String>> {
+        let ips: Vec<(String,)> = sqlx::query_as(
+            "select ip_addr from phalanx_seen_ips where blocked_at > NOW() - make_interval(secs => $1)",
+        )
+        .bind(within_secs as f64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ips.into_iter().map(|ip| ip.0).collect())
+    }
+
+    async fn host_key(&self, ip_addr: &str) -> anyhow::Result<Option<String>> {
+        let key: Option<(String,)> =
+            sqlx::query_as("select host_key from bridge_host_keys where ip_addr = $1")
+                .bind(ip_addr)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(key.map(|k| k.0))
+    }
+
+    async fn set_host_key(&self, ip_addr: &str, host_key: &str) -> anyhow::Result<()> {
+        sqlx::query("insert into bridge_host_keys (ip_addr, host_key) values ($1, $2) on conflict (ip_addr) do update set host_key = excluded.host_key")
+            .bind(ip_addr)
+            .bind(host_key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_probe(
+        &self,
+        bridge_id: &str,
+        probe: &str,
+        verdict: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query("insert into bridge_probe_history (bridge_id, probe, verdict, probe_time) values ($1, $2, $3, NOW())")
+            .bind(bridge_id)
+            .bind(probe)
+            .bind(verdict)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn recent_probes(
+        &self,
+        bridge_id: &str,
+        window_secs: i64,
+    ) -> anyhow::Result<Vec<ProbeRecord>> {
+        Ok(sqlx::query_as(
+            "select * from bridge_probe_history where bridge_id = $1 and probe_time > NOW() - make_interval(secs => $2) order by probe_time desc, id desc",
+        )
+        .bind(bridge_id)
+        .bind(window_secs as f64)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn forget_probes(&self, older_than_secs: i64) -> anyhow::Result<u64> {
+        let result = sqlx::query(
+            "delete from bridge_probe_history where probe_time < NOW() - make_interval(secs => $1)",
+        )
+        .bind(older_than_secs as f64)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn record_block(
+        &self,
+        bridge_id: &str,
+        ip_addr: &str,
+        evidence: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query("insert into bridge_block_evidence (bridge_id, ip_addr, decided_at, evidence) values ($1, $2, NOW(), $3)")
+            .bind(bridge_id)
+            .bind(ip_addr)
+            .bind(evidence)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_origin(
+        &self,
+        bridge_id: &str,
+        group: &str,
+        provider: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query("insert into bridge_lifecycles (bridge_id, alloc_group, provider, created_at) values ($1, $2, $3, NOW()) on conflict do nothing")
+            .bind(bridge_id)
+            .bind(group)
+            .bind(provider)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn lifecycles(&self, group: &str) -> anyhow::Result<Vec<Lifecycle>> {
+        Ok(
+            sqlx::query_as("select * from bridge_lifecycles where alloc_group = $1")
+                .bind(group)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn migrate(&self) -> anyhow::Result<()> {
+        self.pool.execute(MIGRATIONS).await?;
+        Ok(())
+    }
+}
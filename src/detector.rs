@@ -0,0 +1,88 @@
+pub mod dns;
+pub mod ping;
+pub mod quorum;
+pub mod tcp;
+pub mod vantage;
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::{
+    config::GfwConfig,
+    database::BridgeInfo,
+    ssh::{retire_if_compromised, ssh_execute},
+};
+
+use self::{
+    dns::DnsDetector, ping::PingDetector, quorum::Quorum, tcp::TcpDetector,
+    vantage::VantageDetector,
+};
+
+/// What a probe concluded about a bridge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Blocked,
+    Reachable,
+    /// The probe could not tell, so it abstains.
+    Unknown,
+}
+
+impl Verdict {
+    /// How the verdict is recorded in the probe history.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Blocked => "blocked",
+            Verdict::Reachable => "reachable",
+            Verdict::Unknown => "unknown",
+        }
+    }
+}
+
+/// A way of telling whether the GFW blocks a bridge.
+#[async_trait]
+pub trait BlockDetector: Send + Sync + 'static {
+    /// Probes the bridge once.
+    async fn probe(&self, bridge: &BridgeInfo) -> anyhow::Result<Verdict>;
+}
+
+/// Every probe the config enables, combined by quorum.
+pub fn configured_detector(cfg: &GfwConfig) -> Quorum {
+    let mut detectors: Vec<(String, Box<dyn BlockDetector>)> = vec![];
+    if cfg.ping {
+        detectors.push(("ping".into(), Box::new(PingDetector)));
+    }
+    if !cfg.tcp_targets.is_empty() {
+        detectors.push((
+            "tcp".into(),
+            Box::new(TcpDetector::new(cfg.tcp_targets.clone())),
+        ));
+    }
+    if !cfg.dns_resolvers.is_empty() {
+        detectors.push((
+            "dns".into(),
+            Box::new(DnsDetector::new(
+                cfg.dns_resolvers.clone(),
+                cfg.dns_domain.clone(),
+            )),
+        ));
+    }
+    for (i, url) in cfg.vantage_agents.iter().enumerate() {
+        detectors.push((
+            format!("vantage-{i}"),
+            Box::new(VantageDetector::new(url.clone())),
+        ));
+    }
+    Quorum::new(detectors, cfg.quorum)
+}
+
+/// Runs a probe command on the bridge itself, retiring the bridge if it turns out to be impersonated.
+async fn run_on_bridge(bridge: &BridgeInfo, cmd: &str) -> anyhow::Result<String> {
+    match ssh_execute(&bridge.ip_addr, cmd, Duration::from_secs(30)).await {
+        Ok(output) => Ok(output.stdout),
+        Err(err) => {
+            retire_if_compromised(&bridge.bridge_id, &err).await?;
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use async_trait::async_trait;
+
+use crate::database::BridgeInfo;
+
+use super::{run_on_bridge, BlockDetector, Verdict};
+
+/// Asks resolvers in China, from the bridge, for a name that resolves to the bridge's own address. Silence means that the bridge's traffic no longer gets through, and any other address means that the GFW answered in the resolver's place.
+pub struct DnsDetector {
+    resolvers: Vec<String>,
+    domain: String,
+}
+
+impl DnsDetector {
+    pub fn new(resolvers: Vec<String>, domain: String) -> Self {
+        Self { resolvers, domain }
+    }
+}
+
+#[async_trait]
+impl BlockDetector for DnsDetector {
+    async fn probe(&self, bridge: &BridgeInfo) -> anyhow::Result<Verdict> {
+        let domain = self.domain.replace("{ip}", &bridge.ip_addr);
+        let queries = self
+            .resolvers
+            .iter()
+            .map(|resolver| format!("dig +time=3 +tries=1 +short @{resolver} {domain} A || true"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let output = run_on_bridge(
+            bridge,
+            &format!("command -v dig > /dev/null || {{ echo nodig; exit 0; }}; {queries}"),
+        )
+        .await?;
+        Ok(if output.contains("nodig") {
+            Verdict::Unknown
+        } else if output.lines().any(|line| line.trim() == bridge.ip_addr) {
+            Verdict::Reachable
+        } else {
+            if !output.trim().is_empty() {
+                log::debug!("{} got poisoned answers: {}", bridge.ip_addr, output.trim());
+            }
+            Verdict::Blocked
+        })
+    }
+}
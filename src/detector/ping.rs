@@ -0,0 +1,23 @@
+use async_trait::async_trait;
+
+use crate::database::BridgeInfo;
+
+use super::{run_on_bridge, BlockDetector, Verdict};
+
+/// Pings an address in China from the bridge. ICMP gets filtered for all sorts of reasons, so this is weak evidence on its own.
+pub struct PingDetector;
+
+#[async_trait]
+impl BlockDetector for PingDetector {
+    async fn probe(&self, bridge: &BridgeInfo) -> anyhow::Result<Verdict> {
+        let output =
+            run_on_bridge(bridge, "ping -i 0.1 -W 1 -c 10 123.123.123.123 || true").await?;
+        Ok(if output.contains("100%") {
+            Verdict::Blocked
+        } else if output.contains("packet loss") {
+            Verdict::Reachable
+        } else {
+            Verdict::Unknown
+        })
+    }
+}
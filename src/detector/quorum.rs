@@ -0,0 +1,59 @@
+use async_trait::async_trait;
+use futures_util::future::join_all;
+
+use crate::database::BridgeInfo;
+
+use super::{BlockDetector, Verdict};
+
+/// Combines several detectors, so that no single flaky probe decides a bridge's fate. A verdict needs at least `quorum` probes behind it, and more than the opposite verdict has; failed probes abstain.
+pub struct Quorum {
+    detectors: Vec<(String, Box<dyn BlockDetector>)>,
+    quorum: usize,
+}
+
+impl Quorum {
+    pub fn new(detectors: Vec<(String, Box<dyn BlockDetector>)>, quorum: usize) -> Self {
+        Self { detectors, quorum }
+    }
+
+    /// Runs every detector on the bridge, naming each one's verdict. Failed probes count as unknown.
+    pub async fn votes(&self, bridge: &BridgeInfo) -> Vec<(String, Verdict)> {
+        let verdicts = join_all(self.detectors.iter().map(|(_, d)| d.probe(bridge))).await;
+        self.detectors
+            .iter()
+            .zip(verdicts)
+            .map(|((name, _), verdict)| {
+                let verdict = verdict.unwrap_or_else(|err| {
+                    log::debug!("{name} could not probe {}: {:?}", bridge.ip_addr, err);
+                    Verdict::Unknown
+                });
+                (name.clone(), verdict)
+            })
+            .collect()
+    }
+
+    /// Combines the votes into the verdict of the whole round.
+    pub fn tally(&self, bridge: &BridgeInfo, votes: &[(String, Verdict)]) -> Verdict {
+        let count = |verdict| votes.iter().filter(|(_, v)| *v == verdict).count();
+        let (blocked, reachable) = (count(Verdict::Blocked), count(Verdict::Reachable));
+        log::debug!(
+            "{}: {blocked} probes say blocked, {reachable} say reachable",
+            bridge.ip_addr
+        );
+        if blocked >= self.quorum && blocked > reachable {
+            Verdict::Blocked
+        } else if reachable >= self.quorum && reachable > blocked {
+            Verdict::Reachable
+        } else {
+            Verdict::Unknown
+        }
+    }
+}
+
+#[async_trait]
+impl BlockDetector for Quorum {
+    async fn probe(&self, bridge: &BridgeInfo) -> anyhow::Result<Verdict> {
+        let votes = self.votes(bridge).await;
+        Ok(self.tally(bridge, &votes))
+    }
+}
@@ -0,0 +1,40 @@
+use async_trait::async_trait;
+
+use crate::database::BridgeInfo;
+
+use super::{run_on_bridge, BlockDetector, Verdict};
+
+/// Opens TCP connections from the bridge to servers in China. The bridge counts as reachable if any of them accepts.
+pub struct TcpDetector {
+    /// Targets as `host:port`.
+    targets: Vec<String>,
+}
+
+impl TcpDetector {
+    pub fn new(targets: Vec<String>) -> Self {
+        Self { targets }
+    }
+}
+
+#[async_trait]
+impl BlockDetector for TcpDetector {
+    async fn probe(&self, bridge: &BridgeInfo) -> anyhow::Result<Verdict> {
+        let cmd = self
+            .targets
+            .iter()
+            .filter_map(|target| target.rsplit_once(':'))
+            .map(|(host, port)| {
+                format!("(timeout 5 bash -c '</dev/tcp/{host}/{port}' && echo open || echo closed)")
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        let output = run_on_bridge(bridge, &cmd).await?;
+        Ok(if output.lines().any(|line| line.trim() == "open") {
+            Verdict::Reachable
+        } else if output.lines().any(|line| line.trim() == "closed") {
+            Verdict::Blocked
+        } else {
+            Verdict::Unknown
+        })
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use isahc::{config::Configurable, AsyncReadResponseExt, Request, RequestExt};
+use serde::Deserialize;
+
+use crate::database::BridgeInfo;
+
+use super::{BlockDetector, Verdict};
+
+/// Asks an agent running inside China whether it can reach the bridge.
+///
+/// The agent gets `GET {url}?ip={ip}` and answers with `{"reachable": true}` or `{"reachable": false}`.
+pub struct VantageDetector {
+    url: String,
+}
+
+impl VantageDetector {
+    pub fn new(url: String) -> Self {
+        Self { url }
+    }
+}
+
+#[derive(Deserialize)]
+struct Report {
+    reachable: bool,
+}
+
+#[async_trait]
+impl BlockDetector for VantageDetector {
+    async fn probe(&self, bridge: &BridgeInfo) -> anyhow::Result<Verdict> {
+        let mut resp = Request::get(format!("{}?ip={}", self.url, bridge.ip_addr))
+            .timeout(Duration::from_secs(30))
+            .body(())?
+            .send_async()
+            .await
+            .with_context(|| format!("vantage agent {} unreachable", self.url))?;
+        if !resp.status().is_success() {
+            anyhow::bail!("vantage agent {} said {}", self.url, resp.status());
+        }
+        let report: Report = resp.json().await?;
+        Ok(if report.reachable {
+            Verdict::Reachable
+        } else {
+            Verdict::Blocked
+        })
+    }
+}
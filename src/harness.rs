@@ -0,0 +1,893 @@
+//! End-to-end tests that run the control loops against a [MockProvider] and an in-memory database.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_compat::{Compat, CompatExt};
+use isahc::{AsyncReadResponseExt, Request, RequestExt};
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    admin::serve_admin,
+    analytics::summarize,
+    config::{
+        group_configs, load_config, replace_group_configs, AdminConfig, Config, CreateLimit,
+        GfwConfig, GroupConfig, IpFreshness, ProviderConfig, Service,
+    },
+    database::{sqlite::SqliteStore, BridgeInfo, BridgeStore, Lifecycle, DATABASE},
+    detector::{configured_detector, dns::DnsDetector, BlockDetector, Verdict},
+    loop_frontline::loop_frontline,
+    loop_gfw::{decide, loop_gfw, probe_bridge},
+    loop_provision::loop_provision,
+    loop_prune::loop_prune,
+    plan::DryRunStore,
+    provider::{
+        failover::{Failover, Member},
+        ip_fresher::IpFresher,
+        mock::{update_host, MockConfig, MockProvider},
+        throttle::{health, Health, Throttled},
+        vultr::VultrConfig,
+        Provider,
+    },
+    ssh::pin_host_key,
+};
+
+/// The configuration every test runs under, since the loops read the global [CONFIG].
+pub fn config() -> Config {
+    let groups = [
+        ("harness-steady", 2, 3),
+        ("harness-flaky", 1, 3),
+        ("harness-fresh", 0, 5),
+        ("harness-orphans", 0, 1),
+        ("harness-gfw", 1, 1),
+        ("harness-mitm", 1, 1),
+        ("harness-broken", 0, 3),
+        ("harness-resume", 0, 2),
+        ("harness-admin", 0, 0),
+        ("harness-throttle", 0, 2),
+        ("harness-failover", 0, 3),
+        ("harness-reload", 0, 1),
+    ]
+    .into_iter()
+    .map(|(name, frontline, reserve)| (name.to_string(), group_config(frontline, reserve)))
+    .collect::<BTreeMap<_, _>>();
+    Config {
+        database_url: "sqlite::memory:".into(),
+        bridge_secret: "harness".into(),
+        groups,
+        admin: None,
+        dry_run: false,
+        // so that blocked bridges are pruned within a test
+        gfw: GfwConfig {
+            window_mins: 1,
+            ..Default::default()
+        },
+    }
+}
+
+fn group_config(frontline: usize, reserve: usize) -> GroupConfig {
+    GroupConfig {
+        frontline,
+        max_frontline: None,
+        reserve,
+        override_group: None,
+        no_antigfw: false,
+        // never used, since the harness hands the loops a mock provider directly
+        provider: Some(ProviderConfig::Vultr(VultrConfig {
+            api_key: String::new(),
+            sshkey_id: String::new(),
+            region: String::new(),
+            plan: String::new(),
+            os_id: 0,
+        })),
+        providers: vec![],
+        avg_lifetime_hr: 1_000_000.0,
+        services: vec![Service::Geph5],
+        max_bandwidth_gb: None,
+        target_mbps: 100.0,
+        exit_country: None,
+        exit_city: None,
+        exit_total_ratelimit: None,
+        create_limit: Default::default(),
+        fresh_ips: None,
+        ip_freshness: Default::default(),
+        weight_by_survival: false,
+    }
+}
+
+async fn count(group: &str, status: &str) -> i64 {
+    DATABASE
+        .status_counts(group)
+        .await
+        .unwrap()
+        .get(status)
+        .copied()
+        .unwrap_or_default()
+}
+
+async fn status_of(bridge_id: &str) -> Option<String> {
+    DATABASE
+        .all_bridges()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|b| b.bridge_id == bridge_id)
+        .map(|b| b.status)
+}
+
+/// Polls the condition until it holds, panicking after three minutes, since every test shares one in-memory database.
+async fn wait_until<F: Future<Output = bool>>(what: &str, mut cond: impl FnMut() -> F) {
+    let start = Instant::now();
+    while !cond().await {
+        if start.elapsed() > Duration::from_secs(180) {
+            panic!("timed out waiting until {what}");
+        }
+        smol::Timer::after(Duration::from_millis(200)).await;
+    }
+}
+
+#[test]
+fn provision_fills_reserve_and_frontline() {
+    smol::block_on(async {
+        let group = "harness-steady";
+        let provider = Arc::new(MockProvider::new(MockConfig::default()));
+        let _provision = smol::spawn(loop_provision(group.into(), provider.clone()));
+        let _frontline = smol::spawn(loop_frontline(group.into()));
+        wait_until("the group is at strength", || async {
+            count(group, "frontline").await == 2 && count(group, "reserve").await == 3
+        })
+        .await;
+        assert_eq!(provider.servers().len(), 5);
+    })
+}
+
+#[test]
+fn provision_survives_flaky_provider() {
+    smol::block_on(async {
+        let group = "harness-flaky";
+        let provider = Arc::new(MockProvider::new(MockConfig {
+            latency: Duration::from_millis(100),
+            failure_rate: 1.0,
+            ..Default::default()
+        }));
+        let _provision = smol::spawn(loop_provision(group.into(), provider.clone()));
+        let _frontline = smol::spawn(loop_frontline(group.into()));
+        smol::Timer::after(Duration::from_secs(3)).await;
+        assert_eq!(count(group, "reserve").await, 0);
+        provider.set_config(MockConfig {
+            latency: Duration::from_millis(100),
+            failure_rate: 0.5,
+            ..Default::default()
+        });
+        wait_until("the group is at strength", || async {
+            count(group, "frontline").await == 1 && count(group, "reserve").await == 3
+        })
+        .await;
+    })
+}
+
+#[test]
+fn failing_provider_is_throttled() {
+    smol::block_on(async {
+        let group = "harness-throttle";
+        let mock = Arc::new(MockProvider::new(MockConfig {
+            failure_rate: 1.0,
+            ..Default::default()
+        }));
+        let provider = Arc::new(Throttled::new(
+            group,
+            "mock",
+            "mock:throttle",
+            CreateLimit {
+                per_minute: 600.0,
+                burst: 2,
+                max_backoff_secs: 1,
+                breaker_threshold: 3,
+                breaker_cooldown_secs: 5,
+            },
+            mock.clone(),
+        ));
+        let _provision = smol::spawn(loop_provision(group.into(), provider));
+        wait_until("the provider is unhealthy", || async {
+            health(group, "mock") == Health::Unhealthy
+        })
+        .await;
+        // nothing gets through while the breaker is open
+        let attempts = mock.attempts();
+        smol::Timer::after(Duration::from_secs(3)).await;
+        assert_eq!(mock.attempts(), attempts);
+
+        mock.set_config(MockConfig::default());
+        wait_until("the reserve is full", || async {
+            count(group, "reserve").await == 2
+        })
+        .await;
+        assert_eq!(health(group, "mock"), Health::Healthy);
+    })
+}
+
+#[test]
+fn failover_skips_failing_providers() {
+    smol::block_on(async {
+        let group = "harness-failover";
+        let limit = CreateLimit {
+            per_minute: 600.0,
+            burst: 5,
+            max_backoff_secs: 1,
+            breaker_threshold: 2,
+            breaker_cooldown_secs: 3600,
+        };
+        let primary = Arc::new(MockProvider::new(MockConfig {
+            failure_rate: 1.0,
+            ..Default::default()
+        }));
+        let secondary = Arc::new(MockProvider::new(MockConfig::default()));
+        let member = |name: &str, priority, mock: &Arc<MockProvider>| Member {
+            name: name.into(),
+            weight: 1.0,
+            priority,
+            provider: Arc::new(Throttled::new(
+                group,
+                name,
+                &format!("mock:{name}"),
+                limit.clone(),
+                mock.clone(),
+            )),
+        };
+        let provider = Arc::new(Failover::new(
+            group,
+            vec![
+                member("primary", 0, &primary),
+                member("secondary", 1, &secondary),
+            ],
+        ));
+        let orphans = [primary.add_orphan(), secondary.add_orphan()];
+        let _provision = smol::spawn(loop_provision(group.into(), provider));
+        wait_until("the reserve is full", || async {
+            count(group, "reserve").await == 3
+        })
+        .await;
+        assert_ne!(health(group, "primary"), Health::Healthy);
+        wait_until("both providers are swept", || async {
+            primary.servers().is_empty() && secondary.servers().len() == 3
+        })
+        .await;
+        assert!(secondary
+            .servers()
+            .iter()
+            .all(|(id, _)| !orphans.contains(id)));
+    })
+}
+
+#[test]
+fn ip_fresher_rejects_duplicate_ips() {
+    smol::block_on(async {
+        let group = "harness-fresh";
+        let provider: Arc<dyn Provider> = Arc::new(IpFresher::new(
+            MockProvider::new(MockConfig {
+                duplicate_ip_rate: 0.5,
+                ..Default::default()
+            }),
+            Default::default(),
+        ));
+        let _provision = smol::spawn(loop_provision(group.into(), provider));
+        wait_until("the reserve is full", || async {
+            count(group, "reserve").await == 5
+        })
+        .await;
+        let ips: Vec<String> = DATABASE
+            .group_bridges(group, "reserve")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|b| b.ip_addr)
+            .collect();
+        let distinct: HashSet<_> = ips.iter().collect();
+        assert_eq!(distinct.len(), ips.len());
+    })
+}
+
+#[test]
+fn ip_fresher_gives_up_after_its_budget() {
+    smol::block_on(async {
+        let burned = MockProvider::new(MockConfig::default())
+            .create_server()
+            .await
+            .unwrap();
+        DATABASE.record_blocked_ip(&burned.ip_addr).await.unwrap();
+        // the mock hands out neighbouring IPs, which share the burned one's /16
+        let mock = Arc::new(MockProvider::new(MockConfig::default()));
+        let subnet_aware = IpFresher::new(
+            mock.clone(),
+            IpFreshness {
+                max_attempts: 3,
+                block_prefix: Some(16),
+                ..Default::default()
+            },
+        );
+        let Err(err) = subnet_aware.create_server().await else {
+            panic!("a server in a burned subnet was accepted")
+        };
+        assert!(err.to_string().contains("after 3 attempts"));
+        // rejected servers are deleted right away, without waiting for a sweep
+        assert!(mock.servers().is_empty());
+
+        let mock = Arc::new(MockProvider::new(MockConfig {
+            duplicate_ip_rate: 1.0,
+            ..Default::default()
+        }));
+        let repeating = IpFresher::new(
+            mock.clone(),
+            IpFreshness {
+                max_attempts: 3,
+                ..Default::default()
+            },
+        );
+        repeating.create_server().await.unwrap();
+        assert!(repeating.create_server().await.is_err());
+        assert_eq!(mock.servers().len(), 1);
+    })
+}
+
+#[test]
+fn every_rejected_ip_spends_a_create_token() {
+    smol::block_on(async {
+        let mock = Arc::new(MockProvider::new(MockConfig {
+            duplicate_ip_rate: 1.0,
+            ..Default::default()
+        }));
+        let seen = mock.create_server().await.unwrap();
+        DATABASE.claim_seen_ip(&seen.ip_addr).await.unwrap();
+        let fresher = IpFresher::new(
+            Throttled::new(
+                "harness-tokens",
+                "mock",
+                "mock:tokens",
+                CreateLimit {
+                    per_minute: 1.0,
+                    burst: 1,
+                    ..Default::default()
+                },
+                mock.clone(),
+            ),
+            IpFreshness {
+                max_attempts: 5,
+                ..Default::default()
+            },
+        );
+        // the only token goes to the first stale server, and the retry waits for the next one
+        assert!(fresher
+            .create_server()
+            .timeout(Duration::from_secs(3))
+            .await
+            .is_none());
+        assert_eq!(mock.attempts(), 2);
+    })
+}
+
+#[test]
+fn broken_installs_are_destroyed() {
+    smol::block_on(async {
+        let group = "harness-broken";
+        let provider = Arc::new(MockProvider::new(MockConfig {
+            broken_rate: 0.5,
+            ..Default::default()
+        }));
+        let _provision = smol::spawn(loop_provision(group.into(), provider.clone()));
+        wait_until("the reserve is full", || async {
+            count(group, "reserve").await == 3
+        })
+        .await;
+        wait_until("only the recorded servers are left", || async {
+            provider.servers().len() == 3
+        })
+        .await;
+        for bridge in DATABASE.group_bridges(group, "reserve").await.unwrap() {
+            let mut broken = false;
+            update_host(&bridge.ip_addr, |host| broken = host.broken);
+            assert!(!broken, "{} is broken", bridge.ip_addr);
+        }
+    })
+}
+
+#[test]
+fn unfinished_provisioning_is_taken_over() {
+    smol::block_on(async {
+        let group = "harness-resume";
+        let provider = Arc::new(MockProvider::new(MockConfig::default()));
+        // what a crashed earlier run leaves behind: one bridge at each stage, plus one that can never finish
+        let creating = provider.create_server().await.unwrap();
+        DATABASE
+            .insert_bridge(&creating.id, &creating.ip_addr, group, "creating")
+            .await
+            .unwrap();
+        let installing = provider.create_server().await.unwrap();
+        pin_host_key(&installing.ip_addr).await.unwrap();
+        DATABASE
+            .insert_bridge(&installing.id, &installing.ip_addr, group, "installing")
+            .await
+            .unwrap();
+        let broken = provider.create_server().await.unwrap();
+        pin_host_key(&broken.ip_addr).await.unwrap();
+        update_host(&broken.ip_addr, |host| host.broken = true);
+        DATABASE
+            .insert_bridge(&broken.id, &broken.ip_addr, group, "installing")
+            .await
+            .unwrap();
+
+        let _provision = smol::spawn(loop_provision(group.into(), provider.clone()));
+        wait_until("the broken bridge is torn down", || async {
+            provider.servers().iter().all(|(id, _)| id != &broken.id)
+        })
+        .await;
+        wait_until("the reserve is full", || async {
+            count(group, "reserve").await == 2
+        })
+        .await;
+        let mut reserve: Vec<String> = DATABASE
+            .group_bridges(group, "reserve")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|b| b.bridge_id)
+            .collect();
+        reserve.sort();
+        let mut resumed = vec![creating.id, installing.id];
+        resumed.sort();
+        assert_eq!(reserve, resumed);
+        assert_eq!(provider.servers().len(), 2);
+
+        // a bridge that turns up unfinished later belongs to whoever is provisioning it, such as a one-off command, until it has been left for too long
+        let foreign = provider.create_server().await.unwrap();
+        DATABASE
+            .insert_bridge(&foreign.id, &foreign.ip_addr, group, "creating")
+            .await
+            .unwrap();
+        smol::Timer::after(Duration::from_secs(5)).await;
+        assert_eq!(count(group, "creating").await, 1);
+        wait_until("the abandoned bridge is taken on", || async {
+            DATABASE
+                .group_bridges(group, "reserve")
+                .await
+                .unwrap()
+                .iter()
+                .any(|b| b.bridge_id == foreign.id)
+        })
+        .await;
+        assert_eq!(count(group, "creating").await, 0);
+        assert_eq!(provider.servers().len(), 3);
+    })
+}
+
+#[test]
+fn orphans_are_swept() {
+    smol::block_on(async {
+        let group = "harness-orphans";
+        let provider = Arc::new(MockProvider::new(MockConfig::default()));
+        let orphan = provider.add_orphan();
+        let _provision = smol::spawn(loop_provision(group.into(), provider.clone()));
+        wait_until("the orphan is deleted", || async {
+            provider.servers().iter().all(|(id, _)| id != &orphan)
+        })
+        .await;
+        wait_until("the reserve is full", || async {
+            count(group, "reserve").await == 1
+        })
+        .await;
+    })
+}
+
+#[test]
+fn blocked_bridges_are_replaced() {
+    smol::block_on(async {
+        let group = "harness-gfw";
+        let provider = Arc::new(MockProvider::new(MockConfig::default()));
+        let _provision = smol::spawn(loop_provision(group.into(), provider.clone()));
+        let _frontline = smol::spawn(loop_frontline(group.into()));
+        let _gfw = smol::spawn(loop_gfw());
+        let _prune = smol::spawn(loop_prune());
+        wait_until("a bridge is in the frontline", || async {
+            count(group, "frontline").await == 1
+        })
+        .await;
+        let victim = DATABASE
+            .group_bridges(group, "frontline")
+            .await
+            .unwrap()
+            .remove(0);
+        update_host(&victim.ip_addr, |host| host.blocked = true);
+        wait_until("the bridge is blocked and its place taken", || async {
+            status_of(&victim.bridge_id).await.as_deref() == Some("blocked")
+                && count(group, "frontline").await == 1
+        })
+        .await;
+        // a block that lifts before the bridge is pruned sends it back to the reserve
+        update_host(&victim.ip_addr, |host| host.blocked = false);
+        wait_until("the bridge is unblocked", || async {
+            status_of(&victim.bridge_id).await.as_deref() == Some("reserve")
+        })
+        .await;
+        update_host(&victim.ip_addr, |host| host.blocked = true);
+        wait_until_replaced(&provider, group, &victim).await;
+    })
+}
+
+#[test]
+fn mitm_bridges_are_retired() {
+    smol::block_on(async {
+        let group = "harness-mitm";
+        let provider = Arc::new(MockProvider::new(MockConfig::default()));
+        let _provision = smol::spawn(loop_provision(group.into(), provider.clone()));
+        let _frontline = smol::spawn(loop_frontline(group.into()));
+        let _gfw = smol::spawn(loop_gfw());
+        let _prune = smol::spawn(loop_prune());
+        wait_until("a bridge is in the frontline", || async {
+            count(group, "frontline").await == 1
+        })
+        .await;
+        let victim = DATABASE
+            .group_bridges(group, "frontline")
+            .await
+            .unwrap()
+            .remove(0);
+        update_host(&victim.ip_addr, |host| {
+            host.host_key = "SHA256:0123456789abcdef".into()
+        });
+        wait_until_replaced(&provider, group, &victim).await;
+    })
+}
+
+/// Waits until the victim is gone from both the database and the provider, with another bridge in the frontline in its place.
+async fn wait_until_replaced(provider: &MockProvider, group: &str, victim: &BridgeInfo) {
+    wait_until("the bridge is replaced", || async {
+        let victim_left = DATABASE
+            .all_bridges()
+            .await
+            .unwrap()
+            .iter()
+            .any(|b| b.bridge_id == victim.bridge_id);
+        !victim_left && count(group, "frontline").await == 1
+    })
+    .await;
+    wait_until("the server is deleted", || async {
+        provider
+            .servers()
+            .iter()
+            .all(|(id, _)| id != &victim.bridge_id)
+    })
+    .await;
+}
+
+#[test]
+fn admin_api_controls_bridges() {
+    smol::block_on(Compat::new(async {
+        let group = "harness-admin";
+        for id in ["admin-one", "admin-two"] {
+            DATABASE
+                .insert_bridge(id, &format!("192.0.2.{}", id.len()), group, "reserve")
+                .await
+                .unwrap();
+        }
+        let listen = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let _admin = smol::spawn(
+            serve_admin(AdminConfig {
+                listen,
+                token: "hunter2".into(),
+            })
+            .compat(),
+        );
+        smol::Timer::after(Duration::from_millis(500)).await;
+
+        let call = |method: &str, path: &str, token: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(format!("http://{listen}{path}"))
+                .header("Authorization", format!("Bearer {token}"))
+                .header("Content-Type", "application/json")
+                .body(body.to_string())
+                .unwrap()
+                .send_async()
+        };
+        let resp = call("GET", "/groups", "wrong", "").await.unwrap();
+        assert_eq!(resp.status(), 401);
+
+        let mut resp = call("GET", "/groups", "hunter2", "").await.unwrap();
+        let groups: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(groups[group]["counts"]["reserve"], 2);
+
+        let mut resp = call("GET", &format!("/groups/{group}/bridges"), "hunter2", "")
+            .await
+            .unwrap();
+        let bridges: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(bridges.as_array().unwrap().len(), 2);
+
+        let resp = call(
+            "POST",
+            "/bridges/admin-one/status",
+            "hunter2",
+            r#"{"status": "compromised"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), 400);
+        let resp = call(
+            "POST",
+            "/bridges/admin-one/status",
+            "hunter2",
+            r#"{"status": "frontline"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(count(group, "frontline").await, 1);
+
+        let resp = call("POST", "/bridges/admin-two/retire", "hunter2", "")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 204);
+        assert_eq!(count(group, "reserve").await, 0);
+        let resp = call("POST", "/bridges/admin-two/retire", "hunter2", "")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 404);
+
+        // the provisioning loop keeps the counts up to date, and anyone may scrape them
+        let _provision = smol::spawn(loop_provision(
+            group.into(),
+            Arc::new(MockProvider::new(MockConfig::default())),
+        ));
+        wait_until("the metrics show the frontline bridge", || async {
+            let mut resp = call("GET", "/metrics", "", "").await.unwrap();
+            assert_eq!(resp.status(), 200);
+            let metrics = resp.text().await.unwrap();
+            metrics.contains(r#"phalanx_bridges{group="harness-admin",status="frontline"} 1"#)
+                && metrics.contains(r#"phalanx_bridges{group="harness-admin",status="reserve"} 0"#)
+        })
+        .await;
+    }))
+}
+
+#[test]
+fn dry_runs_only_read_the_database() {
+    smol::block_on(async {
+        let path = std::env::temp_dir().join(format!("phalanx-dry-run-{}.db", fastrand::u64(..)));
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        let real = SqliteStore::new(&url).unwrap();
+        real.insert_bridge("dry-one", "192.0.2.1", "dry", "reserve")
+            .await
+            .unwrap();
+        let dry = DryRunStore::new(SqliteStore::new(&url).unwrap());
+        assert_eq!(
+            dry.pick_reserve("dry").await.unwrap().unwrap().bridge_id,
+            "dry-one"
+        );
+        dry.set_status("dry-one", "frontline").await.unwrap();
+        dry.insert_bridge("dry-two", "192.0.2.2", "dry", "reserve")
+            .await
+            .unwrap();
+        assert_eq!(dry.delete_with_status("reserve", 0).await.unwrap(), 0);
+        assert!(dry.claim_seen_ip("192.0.2.1").await.unwrap());
+        assert!(dry.claim_seen_ip("192.0.2.1").await.unwrap());
+
+        let bridges = real.all_bridges().await.unwrap();
+        assert_eq!(bridges.len(), 1);
+        assert_eq!(bridges[0].status, "reserve");
+        let _ = std::fs::remove_file(path);
+    })
+}
+
+#[test]
+fn bad_configs_are_reported() {
+    let path = std::env::temp_dir().join(format!("phalanx-config-{}.yaml", fastrand::u64(..)));
+    let mut config = serde_yaml::to_value(config()).unwrap();
+    config["groups"]["harness-steady"]["max_frontline"] = 1.into();
+    config["groups"]["harness-steady"]["override_group"] = "harness-nowhere".into();
+    config["groups"]["harness-flaky"]["provider"]["colour"] = "blue".into();
+    config["groups"]["harness-flaky"]["create_limit"]["per_minute"] = 60.0.into();
+    std::fs::write(&path, serde_yaml::to_string(&config).unwrap()).unwrap();
+    let err = format!("{:#}", load_config(&path).unwrap_err());
+    let _ = std::fs::remove_file(&path);
+    for problem in [
+        "groups.harness-steady.max_frontline: is below the frontline of 2",
+        "groups.harness-steady.override_group: there is no group harness-nowhere",
+        "groups.harness-flaky.provider.colour: unknown field",
+        "groups.harness-flaky.create_limit: differs from the one of harness-admin, on the same provider account",
+    ] {
+        assert!(err.contains(problem), "{problem} missing from {err}");
+    }
+    assert_eq!(err.lines().count(), 5);
+}
+
+#[test]
+fn group_changes_apply_without_restart() {
+    smol::block_on(async {
+        let group = "harness-reload";
+        let provider = Arc::new(MockProvider::new(MockConfig::default()));
+        let _provision = smol::spawn(loop_provision(group.into(), provider.clone()));
+        wait_until("the reserve is filled", || async {
+            count(group, "reserve").await == 1
+        })
+        .await;
+
+        let mut groups = group_configs();
+        groups.get_mut(group).unwrap().reserve = 2;
+        replace_group_configs(groups);
+        wait_until("the bigger reserve is filled", || async {
+            count(group, "reserve").await == 2
+        })
+        .await;
+
+        let handed_out = DATABASE.group_bridges(group, "reserve").await.unwrap()[0]
+            .bridge_id
+            .clone();
+        DATABASE.set_status(&handed_out, "frontline").await.unwrap();
+        let mut groups = group_configs();
+        groups.remove(group);
+        replace_group_configs(groups);
+        // the frontline stays up until it ages out, and the reserve with it
+        smol::Timer::after(Duration::from_secs(12)).await;
+        assert_eq!(count(group, "frontline").await, 1);
+        assert_eq!(count(group, "reserve").await, 1);
+        assert_eq!(provider.servers().len(), 2);
+
+        DATABASE.delete_bridge(&handed_out).await.unwrap();
+        wait_until("the removed group is drained", || async {
+            count(group, "reserve").await == 0 && provider.servers().is_empty()
+        })
+        .await;
+    })
+}
+
+#[test]
+fn one_failing_probe_cannot_condemn_a_bridge() {
+    smol::block_on(async {
+        let provider = MockProvider::new(MockConfig::default());
+        let created = provider.create_server().await.unwrap();
+        let bridge = BridgeInfo {
+            bridge_id: created.id,
+            ip_addr: created.ip_addr.clone(),
+            alloc_group: "harness-gfw".into(),
+            status: "frontline".into(),
+            change_time: chrono::Utc::now().naive_utc(),
+            last_mbps: 0.0,
+        };
+        let detector = configured_detector(&GfwConfig::default());
+        update_host(&created.ip_addr, |host| host.icmp_filtered = true);
+        assert_eq!(detector.probe(&bridge).await.unwrap(), Verdict::Reachable);
+        // a forged answer is no sign of the bridge getting through
+        let dns = DnsDetector::new(vec!["223.5.5.5".into()], "{ip}.sslip.io".into());
+        assert_eq!(dns.probe(&bridge).await.unwrap(), Verdict::Reachable);
+        update_host(&created.ip_addr, |host| host.dns_poisoned = true);
+        assert_eq!(dns.probe(&bridge).await.unwrap(), Verdict::Blocked);
+        update_host(&created.ip_addr, |host| host.blocked = true);
+        assert_eq!(detector.probe(&bridge).await.unwrap(), Verdict::Blocked);
+    })
+}
+
+#[test]
+fn blocks_need_several_rounds_of_evidence() {
+    use Verdict::{Blocked, Reachable, Unknown};
+    let cfg = GfwConfig::default();
+    assert_eq!(
+        decide(&cfg, "frontline", &[Blocked, Blocked, Reachable]),
+        None
+    );
+    assert_eq!(
+        decide(
+            &cfg,
+            "frontline",
+            &[Blocked, Unknown, Blocked, Reachable, Blocked]
+        ),
+        Some("blocked")
+    );
+    // blocked rounds that fell out of the latest few are forgiven
+    assert_eq!(
+        decide(
+            &cfg,
+            "reserve",
+            &[Blocked, Reachable, Reachable, Reachable, Reachable, Blocked, Blocked]
+        ),
+        None
+    );
+    assert_eq!(
+        decide(
+            &cfg,
+            "blocked",
+            &[Reachable, Reachable, Reachable, Reachable, Blocked]
+        ),
+        None
+    );
+    assert_eq!(decide(&cfg, "blocked", &[Reachable; 5]), Some("reserve"));
+    for status in ["compromised", "throttled", "creating", "installing"] {
+        assert_eq!(decide(&cfg, status, &[Blocked; 5]), None);
+    }
+
+    smol::block_on(async {
+        let provider = MockProvider::new(MockConfig::default());
+        let created = provider.create_server().await.unwrap();
+        let bridge = BridgeInfo {
+            bridge_id: created.id,
+            ip_addr: created.ip_addr.clone(),
+            alloc_group: "harness-hysteresis".into(),
+            status: "frontline".into(),
+            change_time: chrono::Utc::now().naive_utc(),
+            last_mbps: 0.0,
+        };
+        let detector = configured_detector(&cfg);
+        update_host(&created.ip_addr, |host| host.blocked = true);
+        for _ in 0..2 {
+            probe_bridge(&detector, &bridge).await.unwrap();
+        }
+        update_host(&created.ip_addr, |host| host.blocked = false);
+        probe_bridge(&detector, &bridge).await.unwrap();
+        let history = DATABASE
+            .recent_probes(&bridge.bridge_id, 3600)
+            .await
+            .unwrap();
+        // a reachable round only keeps its verdict, while ping, tcp and dns each keep their vote against a blocked one
+        assert_eq!(history.len(), 9);
+        assert_eq!(
+            (history[0].probe.as_str(), history[0].verdict.as_str()),
+            ("quorum", "reachable")
+        );
+        assert_eq!(history[1].probe, "quorum");
+        assert!(history[1..].iter().all(|p| p.verdict == "blocked"));
+    })
+}
+
+#[test]
+fn provider_stats_follow_bridge_lifecycles() {
+    smol::block_on(async {
+        let store = SqliteStore::new("sqlite::memory:").unwrap();
+        store.record_origin("life", "stats", "vultr").await.unwrap();
+        store
+            .insert_bridge("life", "192.0.2.1", "stats", "creating")
+            .await
+            .unwrap();
+        for status in ["installing", "reserve", "frontline", "blocked"] {
+            store.set_status("life", status).await.unwrap();
+        }
+        store.delete_with_status("blocked", 0).await.unwrap();
+        let lifecycle = store.lifecycles("stats").await.unwrap().remove(0);
+        assert_eq!(lifecycle.provider, "vultr");
+        assert!(lifecycle.reserve_at.is_some() && lifecycle.frontline_at.is_some());
+        assert!(lifecycle.blocked_at.is_some() && lifecycle.ended_at.is_some());
+    });
+
+    let start = chrono::Utc::now().naive_utc();
+    let at = |hr: i64| Some(start + chrono::Duration::hours(hr));
+    let lifecycle = |provider: &str, frontline_hr: i64, blocked_hr: Option<i64>| Lifecycle {
+        bridge_id: fastrand::u64(..).to_string(),
+        alloc_group: "stats".into(),
+        provider: provider.into(),
+        created_at: start,
+        reserve_at: Some(start),
+        frontline_at: at(frontline_hr),
+        blocked_at: blocked_hr.and_then(at),
+        ended_at: blocked_hr.and_then(at),
+    };
+    let stats = summarize(
+        &[
+            lifecycle("short", 1, Some(2)),
+            lifecycle("short", 1, Some(4)),
+            lifecycle("short", 0, None),
+            lifecycle("long", 2, None),
+            lifecycle("long", 0, None),
+        ],
+        at(10).unwrap(),
+    );
+    // the bridge still alive after 10 hours keeps the median at the second block
+    assert_eq!(stats["short"].median_survival_hr, Some(3.0));
+    assert_eq!(stats["short"].block_rate, Some(2.0 / 3.0));
+    assert_eq!(stats["short"].median_reserve_hr, Some(1.0));
+    assert_eq!(stats["long"].median_survival_hr, Some(10.0));
+    assert!(stats["long"].survival_is_lower_bound);
+    assert_eq!(stats["long"].block_rate, Some(0.0));
+}
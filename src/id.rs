@@ -1,3 +1,8 @@
+/// Whether a name could be an ID from [new_id], which is how the untagged servers of older versions are recognized.
+pub fn looks_like_id(name: &str) -> bool {
+    name.matches('-').count() >= 4 && name.chars().all(|c| c.is_ascii_lowercase() || c == '-')
+}
+
 pub fn new_id() -> String {
     format!(
         "{}-{}-{}-{}-{}",
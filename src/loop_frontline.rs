@@ -1,5 +1,4 @@
 use std::{
-    ops::Deref,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -7,47 +6,63 @@ use std::{
     time::Duration,
 };
 
+use dashmap::DashMap;
 use futures_concurrency::future::TryJoin;
+use once_cell::sync::Lazy;
+use serde::Serialize;
 
 use crate::{
-    config::GroupConfig,
-    database::{BridgeInfo, DATABASE},
-    ssh::ssh_execute,
+    config::{group_config, GroupConfig},
+    database::DATABASE,
+    metrics::{DELAY_MS, OVERLOAD, SIGNAL_MBPS},
+    ssh::{retire_if_compromised, ssh_execute},
 };
 
-pub async fn loop_frontline(alloc_group: String, cfg: GroupConfig) {
+/// What the frontline loop of a group last decided.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct FrontlineState {
+    pub adjusted_frontline: usize,
+    /// Average throughput over the target throughput, unknown until first measured.
+    pub overload: Option<f64>,
+}
+
+/// The state of every group's frontline loop, keyed by group.
+pub static FRONTLINE_STATE: Lazy<DashMap<String, FrontlineState>> = Lazy::new(DashMap::new);
+
+pub async fn loop_frontline(alloc_group: String) {
     let adjusted_frontline = {
-        let (current_live,): (i64,) = sqlx::query_as(
-            "select count(*) from bridges where alloc_group = $1 and status = 'frontline'",
-        )
-        .bind(&alloc_group)
-        .fetch_one(DATABASE.deref())
-        .await
-        .expect("could not fetch current live");
-        Arc::new(AtomicUsize::new(cfg.frontline.max(current_live as usize)))
+        let current_live = frontline_count(&alloc_group)
+            .await
+            .expect("could not fetch current live");
+        let frontline = group_config(&alloc_group).map_or(0, |cfg| cfg.frontline);
+        Arc::new(AtomicUsize::new(frontline.max(current_live as usize)))
     };
+    FRONTLINE_STATE.insert(
+        alloc_group.clone(),
+        FrontlineState {
+            adjusted_frontline: adjusted_frontline.load(Ordering::SeqCst),
+            overload: None,
+        },
+    );
     let _lala_loop = {
         let adjusted_frontline = adjusted_frontline.clone();
-        let base_frontline = cfg.frontline;
-        let max_frontline = cfg.max_frontline.unwrap_or(usize::MAX);
-        if base_frontline == 0 {
-            return;
-        }
-
         let alloc_group = alloc_group.clone();
         smol::spawn(async move {
             let mut timer = smol::Timer::interval(Duration::from_secs(600));
             loop {
-                let (current_live,): (i64,) = sqlx::query_as(
-                    "select count(*) from bridges where alloc_group = $1 and status = 'frontline'",
-                )
-                .bind(&alloc_group)
-                .fetch_one(DATABASE.deref())
-                .await
-                .expect("could not fetch current live");
+                let Some(cfg) = live_config(&alloc_group) else {
+                    (&mut timer).await;
+                    continue;
+                };
+                let base_frontline = cfg.frontline;
+                let max_frontline = cfg.max_frontline.unwrap_or(usize::MAX);
+                let current_live = frontline_count(&alloc_group)
+                    .await
+                    .expect("could not fetch current live");
 
                 let fallible = async {
                     let avg_mbps: f64 = signal_mbps(alloc_group.clone()).await?;
+                    SIGNAL_MBPS.with_label_values(&[&alloc_group]).set(avg_mbps);
                     let overload = avg_mbps / cfg.target_mbps;
                     set_overload(&alloc_group, overload).await?;
                     let ideal_frontline = current_live as f64 * overload;
@@ -66,6 +81,13 @@ pub async fn loop_frontline(alloc_group: String, cfg: GroupConfig) {
                             Ordering::SeqCst,
                         );
                     }
+                    FRONTLINE_STATE.insert(
+                        alloc_group.clone(),
+                        FrontlineState {
+                            adjusted_frontline: adjusted_frontline.load(Ordering::SeqCst),
+                            overload: Some(overload),
+                        },
+                    );
                     log::info!(
                         "adjusted frontline of {alloc_group} from {} to {} on overload {overload}",
                         current_live,
@@ -83,38 +105,45 @@ pub async fn loop_frontline(alloc_group: String, cfg: GroupConfig) {
     };
 
     loop {
-        if let Err(err) = loop_frontline_inner(&alloc_group, &cfg, adjusted_frontline.clone()).await
-        {
-            log::warn!("error: {:?}", err)
+        if let Some(cfg) = live_config(&alloc_group) {
+            if let Err(err) =
+                loop_frontline_inner(&alloc_group, &cfg, adjusted_frontline.clone()).await
+            {
+                log::warn!("error: {:?}", err)
+            }
         }
         smol::Timer::after(Duration::from_secs(1)).await;
     }
 }
 
+/// The current config of the group, if it is still there and has a frontline to manage.
+fn live_config(alloc_group: &str) -> Option<GroupConfig> {
+    group_config(alloc_group).filter(|cfg| cfg.frontline > 0)
+}
+
+async fn frontline_count(alloc_group: &str) -> anyhow::Result<i64> {
+    Ok(DATABASE
+        .status_counts(alloc_group)
+        .await?
+        .get("frontline")
+        .copied()
+        .unwrap_or_default())
+}
+
 async fn set_overload(alloc_group: &str, overload: f64) -> anyhow::Result<()> {
     let delay_ms = (overload - 1.2).max(0.0) * 1000.0;
-    sqlx::query(
-        r#"INSERT INTO bridge_group_delays (pool, delay_ms, is_plus)
-VALUES ($1, $2, false)
-ON CONFLICT (pool)
-DO
-UPDATE SET 
-delay_ms = EXCLUDED.delay_ms"#,
-    )
-    .bind(alloc_group)
-    .bind(delay_ms as i32)
-    .execute(&*DATABASE)
-    .await?;
+    OVERLOAD.with_label_values(&[alloc_group]).set(overload);
+    DELAY_MS
+        .with_label_values(&[alloc_group])
+        .set(delay_ms as i64);
+    DATABASE
+        .set_group_delay(alloc_group, delay_ms as i32)
+        .await?;
     Ok(())
 }
 
 async fn signal_mbps(alloc_group: String) -> anyhow::Result<f64> {
-    let addrs: Vec<(String,)> = sqlx::query_as(
-        "select ip_addr from bridges where alloc_group = $1 and status = 'frontline'",
-    )
-    .bind(&alloc_group)
-    .fetch_all(DATABASE.deref())
-    .await?;
+    let bridges = DATABASE.group_bridges(&alloc_group, "frontline").await?;
 
     let speed_measure = r#"
 S1=$(for i in $(ls /sys/class/net | grep -v '^lo$'); do cat /sys/class/net/$i/statistics/rx_bytes; done | awk '{s+=$1} END{printf "%.0f", s}'); \
@@ -123,16 +152,22 @@ S2=$(for i in $(ls /sys/class/net | grep -v '^lo$'); do cat /sys/class/net/$i/st
 awk -v s1="$S1" -v s2="$S2" 'BEGIN {diff=s2-s1; printf "%.2f\n", diff*8/(1024*1024)}'
     "#;
 
-    let futs = addrs
+    let futs = bridges
         .into_iter()
-        .map(|(addr,)| async move {
-            let resp = ssh_execute(&addr, speed_measure).await?;
-            let resp: f64 = resp.trim().parse().unwrap_or_default();
-            sqlx::query("update bridges set last_mbps = $1 where ip_addr = $2")
-                .bind(resp)
-                .bind(addr)
-                .execute(DATABASE.deref())
-                .await?;
+        .map(|bridge| async move {
+            let addr = bridge.ip_addr;
+            let resp = match ssh_execute(&addr, speed_measure, Duration::from_secs(30)).await {
+                Ok(resp) => resp,
+                Err(err) => {
+                    retire_if_compromised(&bridge.bridge_id, &err).await?;
+                    return Err(err);
+                }
+            };
+            if !resp.success() {
+                anyhow::bail!("cannot measure speed of {addr}: {}", resp.stderr.trim())
+            }
+            let resp: f64 = resp.stdout.trim().parse().unwrap_or_default();
+            DATABASE.set_last_mbps(&addr, resp).await?;
             anyhow::Ok(resp)
         })
         .collect::<Vec<_>>();
@@ -150,35 +185,24 @@ awk -v s1="$S1" -v s2="$S2" 'BEGIN {diff=s2-s1; printf "%.2f\n", diff*8/(1024*10
 #[allow(clippy::comparison_chain)]
 async fn loop_frontline_inner(
     alloc_group: &str,
-    _cfg: &GroupConfig,
+    cfg: &GroupConfig,
     adjusted_frontline: Arc<AtomicUsize>,
 ) -> anyhow::Result<()> {
-    let adjusted_frontline = adjusted_frontline.load(Ordering::SeqCst);
-    // when not enough is in the frontline, move to frontline
-    let (frontline_count,): (i64,) = sqlx::query_as(
-        "select count(bridge_id) from bridges where (status = 'frontline' or status = 'blocked') and alloc_group = $1",
-    )
-    .bind(alloc_group)
-    .fetch_one(DATABASE.deref())
-    .await?;
+    // the config may have moved the bounds since the frontline was last adjusted
+    let adjusted_frontline = adjusted_frontline.load(Ordering::SeqCst).clamp(
+        cfg.frontline,
+        cfg.max_frontline.unwrap_or(usize::MAX).max(cfg.frontline),
+    );
+    // when not enough is in the frontline, move to frontline. Blocked bridges wait out the unblock window without serving anyone, so they do not count.
+    let frontline_count = frontline_count(alloc_group).await?;
     if frontline_count < adjusted_frontline as i64 {
         // attempting to move to frontline
-        let movable: Option<BridgeInfo> = sqlx::query_as(
-            "select * from bridges where status = 'reserve' and alloc_group = $1 limit 1",
-        )
-        .bind(alloc_group)
-        .fetch_optional(DATABASE.deref())
-        .await?;
+        let movable = DATABASE.pick_reserve(alloc_group).await?;
         if let Some(movable) = movable {
-            sqlx::query(
-                "update bridges set status = 'frontline', change_time = NOW() where bridge_id = $1",
-            )
-            .bind(movable.bridge_id)
-            .execute(DATABASE.deref())
-            .await?;
+            DATABASE.set_status(&movable.bridge_id, "frontline").await?;
         }
     } else if frontline_count > adjusted_frontline as i64 {
-        sqlx::query("delete from bridges where bridge_id in (select bridge_id from bridges where status = 'frontline' and alloc_group = $1 order by change_time limit 1)").bind(alloc_group).execute(DATABASE.deref()).await?;
+        DATABASE.delete_oldest(alloc_group, "frontline").await?;
     }
     Ok(())
 }
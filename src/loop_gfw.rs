@@ -1,74 +1,122 @@
-use std::{collections::HashSet, ops::Deref, time::Duration};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 use rand::seq::SliceRandom;
 use smol::lock::Semaphore;
 
 use crate::{
-    config::CONFIG,
+    config::{group_configs, GfwConfig, CONFIG},
     database::{BridgeInfo, DATABASE},
-    ssh::ssh_execute,
+    detector::{configured_detector, quorum::Quorum, Verdict},
+    metrics::GFW_BLOCKS,
 };
 
 pub async fn loop_gfw() {
+    let detector = Arc::new(configured_detector(&CONFIG.gfw));
     loop {
-        if let Err(err) = loop_gfw_inner().await {
+        if let Err(err) = loop_gfw_inner(&detector).await {
             log::warn!("error: {:?}", err)
         }
         smol::Timer::after(Duration::from_secs(1)).await;
     }
 }
 
-async fn loop_gfw_inner() -> anyhow::Result<()> {
+async fn loop_gfw_inner(detector: &Arc<Quorum>) -> anyhow::Result<()> {
     // test all the bridges in a random order
-    let mut bridges: Vec<BridgeInfo> = sqlx::query_as("select * from bridges")
-        .fetch_all(DATABASE.deref())
-        .await?;
+    let mut bridges = DATABASE.all_bridges().await?;
     bridges.shuffle(&mut rand::thread_rng());
-    let no_antigfw_groups: HashSet<String> = CONFIG
-        .groups
+    let no_antigfw_groups: HashSet<String> = group_configs()
         .iter()
         .filter(|g| g.1.no_antigfw)
         .map(|g| g.0.clone())
         .collect();
     let mut tasks = vec![];
     for bridge in bridges {
-        if no_antigfw_groups.contains(&bridge.alloc_group) {
+        // bridges still being provisioned may not even accept SSH yet
+        if no_antigfw_groups.contains(&bridge.alloc_group)
+            || bridge.status == "creating"
+            || bridge.status == "installing"
+        {
             continue;
         }
         static SMALL_SEMAPHORE: Semaphore = Semaphore::new(32);
+        let detector = detector.clone();
         tasks.push(smol::spawn(async move {
             let _guard = SMALL_SEMAPHORE.acquire().await;
-            let is_blocked = {
-                ssh_execute(
-                    &bridge.ip_addr,
-                    "ping -i 0.1 -W 1 -c 10 123.123.123.123 || true",
-                )
-                .await?
-                .contains("100%")
-            };
-            if is_blocked {
-                log::debug!(
-                    "[{}] {} BLOCKED BY THE GFW!!!!!!",
-                    bridge.status,
-                    bridge.ip_addr
-                );
-            }
-            if is_blocked && bridge.status != "blocked" {
-                sqlx::query("update bridges set status = 'blocked' where bridge_id = $1")
-                    .bind(bridge.bridge_id)
-                    .execute(DATABASE.deref())
-                    .await?;
-            } else if !is_blocked && bridge.status == "blocked" {
-                sqlx::query("update bridges set status = 'reserve' where bridge_id = $1")
-                    .bind(bridge.bridge_id)
-                    .execute(DATABASE.deref())
-                    .await?;
-            }
-            anyhow::Ok(())
+            probe_bridge(&detector, &bridge).await
         }));
     }
     for task in tasks {
         task.await?;
     }
+    // rounds older than the window no longer count for anything
+    DATABASE.forget_probes(window_secs()).await?;
     Ok(())
 }
+
+/// Probes a bridge once, recording every vote, then decides its status from the latest rounds of probing. A bridge is only blocked, or unblocked, once enough rounds agree, so that no single bad round moves it back and forth.
+pub async fn probe_bridge(detector: &Quorum, bridge: &BridgeInfo) -> anyhow::Result<()> {
+    let votes = detector.votes(bridge).await;
+    let verdict = detector.tally(bridge, &votes);
+    // the votes behind a reachable verdict are no evidence of anything, and would only pile up
+    if verdict != Verdict::Reachable {
+        for (probe, vote) in votes.iter() {
+            DATABASE
+                .record_probe(&bridge.bridge_id, probe, vote.as_str())
+                .await?;
+        }
+    }
+    DATABASE
+        .record_probe(&bridge.bridge_id, "quorum", verdict.as_str())
+        .await?;
+
+    let history = DATABASE
+        .recent_probes(&bridge.bridge_id, window_secs())
+        .await?;
+    let rounds: Vec<Verdict> = history
+        .iter()
+        .filter(|p| p.probe == "quorum")
+        .map(|p| match p.verdict.as_str() {
+            "blocked" => Verdict::Blocked,
+            "reachable" => Verdict::Reachable,
+            _ => Verdict::Unknown,
+        })
+        .collect();
+    match decide(&CONFIG.gfw, &bridge.status, &rounds) {
+        Some("blocked") => {
+            log::warn!("[{}] {} BLOCKED BY THE GFW", bridge.status, bridge.ip_addr);
+            DATABASE
+                .record_block(
+                    &bridge.bridge_id,
+                    &bridge.ip_addr,
+                    &serde_json::to_string(&history)?,
+                )
+                .await?;
+            DATABASE.record_blocked_ip(&bridge.ip_addr).await?;
+            GFW_BLOCKS.with_label_values(&[&bridge.alloc_group]).inc();
+            DATABASE.set_status(&bridge.bridge_id, "blocked").await?;
+        }
+        Some(status) => {
+            log::info!("{} is reachable again", bridge.ip_addr);
+            DATABASE.set_status(&bridge.bridge_id, status).await?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// The status a bridge should move to, if any, given the verdicts of its recent rounds of probing, newest first. Only bridges that serve users can be blocked, since any other status is already on its way out.
+pub fn decide(cfg: &GfwConfig, status: &str, rounds: &[Verdict]) -> Option<&'static str> {
+    let latest = &rounds[..rounds.len().min(cfg.rounds)];
+    let count = |verdict| latest.iter().filter(|v| **v == verdict).count();
+    if matches!(status, "reserve" | "frontline") && count(Verdict::Blocked) >= cfg.block_after {
+        Some("blocked")
+    } else if status == "blocked" && count(Verdict::Reachable) >= cfg.unblock_after {
+        Some("reserve")
+    } else {
+        None
+    }
+}
+
+fn window_secs() -> i64 {
+    CONFIG.gfw.window_mins as i64 * 60
+}
@@ -0,0 +1,74 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    database::DATABASE,
+    provider::lightsail::{id_to_name, LightsailConfig, LightsailProvider},
+};
+
+/// How often to look at the metrics, which Lightsail averages over ten minutes anyway.
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// How many checks in a row the CPU must be above target before the bridge is replaced.
+const CPU_STRIKES: u32 = 3;
+
+/// Replaces the frontline bridges of a group on a Lightsail account whose burstable CPU is getting throttled. The frontline loop promotes a reserve bridge in its place.
+pub async fn loop_lightsail(alloc_group: String, cfg: LightsailConfig) {
+    let provider = LightsailProvider::new(cfg.clone());
+    let mut strikes = HashMap::new();
+    loop {
+        if let Err(err) = loop_lightsail_once(&alloc_group, &cfg, &provider, &mut strikes).await {
+            log::warn!(
+                "{alloc_group}: could not check lightsail bridges: {:?}",
+                err
+            )
+        }
+        smol::Timer::after(CHECK_INTERVAL).await;
+    }
+}
+
+async fn loop_lightsail_once(
+    alloc_group: &str,
+    cfg: &LightsailConfig,
+    provider: &LightsailProvider,
+    strikes: &mut HashMap<String, u32>,
+) -> anyhow::Result<()> {
+    let ours = provider.bridge_ids().await?;
+    let frontline: Vec<_> = DATABASE
+        .group_bridges(alloc_group, "frontline")
+        .await?
+        .into_iter()
+        .filter(|b| ours.contains(&b.bridge_id))
+        .collect();
+    strikes.retain(|id, _| frontline.iter().any(|b| &b.bridge_id == id));
+
+    for bridge in frontline {
+        let name = id_to_name(&bridge.bridge_id);
+        // brand new instances have no datapoints yet
+        let (burst, cpu) = match (
+            provider.burst_capacity_percent(&name).await,
+            provider.cpu_usage_percent(&name).await,
+        ) {
+            (Ok(burst), Ok(cpu)) => (burst, cpu),
+            (Err(err), _) | (_, Err(err)) => {
+                log::debug!("{alloc_group}: no metrics for {name} yet: {:?}", err);
+                continue;
+            }
+        };
+        let count = strikes.entry(bridge.bridge_id.clone()).or_default();
+        if cpu > cfg.target_cpu_usage {
+            *count += 1;
+        } else {
+            *count = 0;
+        }
+        if burst < cfg.min_burst_capacity || *count >= CPU_STRIKES {
+            log::warn!(
+                "{alloc_group}: replacing throttled {} ({}) with burst capacity {burst:.0}% and CPU {cpu:.0}%",
+                bridge.bridge_id,
+                bridge.ip_addr
+            );
+            DATABASE.set_status(&bridge.bridge_id, "throttled").await?;
+            strikes.remove(&bridge.bridge_id);
+        }
+    }
+    Ok(())
+}
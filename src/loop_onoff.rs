@@ -1,14 +1,15 @@
-use std::{ops::Deref, time::Duration};
+use std::time::Duration;
 
 use anyhow::Context;
 use dashmap::DashMap;
-use futures_util::{StreamExt, TryFutureExt, TryStreamExt};
+use futures_util::{StreamExt, TryStreamExt};
 use rand::seq::SliceRandom;
 use smol_timeout::TimeoutExt;
 
 use crate::{
-    database::{BridgeInfo, DATABASE},
-    ssh::ssh_execute,
+    database::DATABASE,
+    plan::{dry_run, plan},
+    ssh::{retire_if_compromised, ssh_execute},
 };
 
 pub async fn loop_onoff() {
@@ -32,9 +33,7 @@ pub async fn loop_onoff() {
 
 /// Synchronizes the in-database status of bridges with whether their systemd service is on.
 async fn loop_onoff_once(last_status: &DashMap<String, String>) -> anyhow::Result<()> {
-    let mut all_bridges: Vec<BridgeInfo> = sqlx::query_as("select * from bridges")
-        .fetch_all(DATABASE.deref())
-        .await?;
+    let mut all_bridges = DATABASE.all_bridges().await?;
 
     all_bridges.shuffle(&mut rand::thread_rng());
 
@@ -56,10 +55,22 @@ async fn loop_onoff_once(last_status: &DashMap<String, String>) -> anyhow::Resul
                     bridge.status
                 );
                 match bridge.status.as_str() {
+                    status @ ("frontline" | "blocked" | "reserve") if dry_run() => {
+                        let action = if status == "frontline" {
+                            "start"
+                        } else {
+                            "stop"
+                        };
+                        plan(
+                            &format!("services {}", bridge.bridge_id),
+                            format!("would {action} the bridge services on {}", bridge.ip_addr),
+                        );
+                    }
                     "frontline" => {
                         ssh_execute(
                             &bridge.ip_addr,
                             "systemctl enable geph4-bridge; (systemctl is-active --quiet geph4-bridge || systemctl start geph4-bridge); systemctl enable geph5-bridge; (systemctl is-active --quiet geph5-bridge || systemctl start geph5-bridge)",
+                            Duration::from_secs(60),
                         )
                         .await?;
                     }
@@ -67,6 +78,7 @@ async fn loop_onoff_once(last_status: &DashMap<String, String>) -> anyhow::Resul
                         ssh_execute(
                             &bridge.ip_addr,
                             "systemctl stop geph4-bridge; systemctl disable geph4-bridge; systemctl stop geph5-bridge; systemctl disable geph5-bridge",
+                            Duration::from_secs(60),
                         )
                         .await?;
                     }
@@ -77,10 +89,14 @@ async fn loop_onoff_once(last_status: &DashMap<String, String>) -> anyhow::Resul
                 last_status.insert(bridge.bridge_id, bridge.status);
                 anyhow::Ok(())
             };
-            tasks.push(task.map_err(move |e| {
-                log::warn!("{}/{} failed: {e}", bb.alloc_group, bb.ip_addr);
-                e
-            }));
+            tasks.push(async move {
+                if let Err(e) = task.await {
+                    log::warn!("{}/{} failed: {e}", bb.alloc_group, bb.ip_addr);
+                    retire_if_compromised(&bb.bridge_id, &e).await?;
+                    return Err(e);
+                }
+                anyhow::Ok(())
+            });
         }
     }
     let v: Vec<()> = futures_util::stream::iter(tasks)
@@ -1,24 +1,88 @@
-use std::{ops::Deref, sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
+use dashmap::DashSet;
 use futures_util::{stream::FuturesUnordered, StreamExt};
+use once_cell::sync::Lazy;
 
 use rand::Rng;
 use smol_timeout::TimeoutExt;
 
 use crate::{
     config::{
-        GroupConfig, Service, CONFIG, EARENDIL_GIST, GEPH4_GIST, GEPH5_GIST, GEPH5_EXIT_SCRIPT, LIMIT_BANDWIDTH_GIST,
+        group_config, GroupConfig, Service, CONFIG, EARENDIL_GIST, GEPH4_GIST, GEPH5_EXIT_SCRIPT,
+        GEPH5_GIST, LIMIT_BANDWIDTH_GIST,
     },
-    database::{BridgeInfo, DATABASE},
+    database::DATABASE,
+    metrics::{observe_stage, set_bridge_counts},
+    plan::{dry_run, plan},
     provider::Provider,
-    ssh::ssh_execute,
+    ssh::{forget_bootstrap_host_keys, pin_host_key, ssh_execute},
 };
 
-pub async fn loop_provision(alloc_group: String, cfg: GroupConfig, provider: Arc<dyn Provider>) {
+/// How long a single deployment script may run on a new bridge.
+const INSTALL_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// How long a bridge may take to get from `creating` or `installing` to the reserve, in any process.
+const PROVISION_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// How long a bridge may sit in `creating` or `installing` before whoever was provisioning it is taken to be gone.
+#[cfg(not(test))]
+const ABANDONED_AFTER: Duration = Duration::from_secs(PROVISION_TIMEOUT.as_secs() + 600);
+
+/// Under test, abandoned bridges are taken on quickly.
+#[cfg(test)]
+const ABANDONED_AFTER: Duration = Duration::from_secs(10);
+
+/// The bridges this process is provisioning right now, which must not be resumed on top of that.
+static PROVISIONING: Lazy<DashSet<String>> = Lazy::new(DashSet::new);
+
+/// The unfinished bridges this process may resume: the ones it found on startup, the ones it abandoned since, and the ones another process, such as a one-off `provision` command, left unfinished for longer than it could still be provisioning them.
+static RESUMABLE: Lazy<DashSet<String>> = Lazy::new(DashSet::new);
+
+/// Marks a bridge as being provisioned by this process, until dropped.
+struct Provisioning(String);
+
+impl Provisioning {
+    /// Claims the bridge, unless this process is already provisioning it.
+    fn claim(bridge_id: &str) -> Option<Self> {
+        PROVISIONING
+            .insert(bridge_id.to_string())
+            .then(|| Self(bridge_id.to_string()))
+    }
+}
+
+impl Drop for Provisioning {
+    fn drop(&mut self) {
+        PROVISIONING.remove(&self.0);
+    }
+}
+
+pub async fn loop_provision(alloc_group: String, provider: Arc<dyn Provider>) {
+    while let Err(err) = adopt_unfinished(&alloc_group).await {
+        log::warn!("{alloc_group} could not list unfinished bridges: {:?}", err);
+        smol::Timer::after(Duration::from_secs(5)).await;
+    }
+    // provisioning can take long, so the bridge counts are kept up to date alongside it
+    let _counts = smol::spawn(loop_counts(alloc_group.clone()));
+    let mut drained = false;
     loop {
         let secs = rand::thread_rng().gen::<f64>() * 5.0;
         smol::Timer::after(Duration::from_secs_f64(secs)).await;
+        let Some(cfg) = group_config(&alloc_group) else {
+            // the group was removed, so let go of its bridges once, but keep going in case it comes back
+            if !drained {
+                match wind_down(&alloc_group, provider.as_ref()).await {
+                    Ok(done) => drained = done,
+                    Err(err) => log::warn!("{alloc_group} could not drain: {:?}", err),
+                }
+            }
+            continue;
+        };
+        drained = false;
         log::info!("***** provision once {alloc_group} *****");
         if let Err(err) = loop_provision_once(&alloc_group, &cfg, provider.as_ref()).await {
             log::warn!("{alloc_group} error: {:?}", err)
@@ -26,105 +90,391 @@ pub async fn loop_provision(alloc_group: String, cfg: GroupConfig, provider: Arc
     }
 }
 
+/// Keeps the bridge count metrics of the group up to date.
+async fn loop_counts(alloc_group: String) {
+    loop {
+        match DATABASE.status_counts(&alloc_group).await {
+            Ok(counts) => set_bridge_counts(&alloc_group, &counts),
+            Err(err) => log::warn!("{alloc_group} could not count bridges: {:?}", err),
+        }
+        smol::Timer::after(Duration::from_secs(5)).await;
+    }
+}
+
 async fn loop_provision_once(
     alloc_group: &str,
     cfg: &GroupConfig,
     provider: &dyn Provider,
 ) -> anyhow::Result<()> {
     async {
-    {
-        let bridges: Vec<BridgeInfo> = sqlx::query_as("select * from bridges")
-            .fetch_all(DATABASE.deref())
-            .await?;
-        provider
-            .retain_by_id(Box::new(move |id| {
-                bridges.iter().any(|b| b.bridge_id == id)
-            }))
-            .await?;
+        sweep_orphans(provider).await?;
+
+        adopt_abandoned(alloc_group).await?;
+        resume_unfinished(alloc_group, cfg, provider).await?;
+
+        let reserve_count = DATABASE
+            .status_counts(alloc_group)
+            .await?
+            .get("reserve")
+            .copied()
+            .unwrap_or_default();
+        if reserve_count < cfg.reserve as i64 {
+            log::debug!(
+                "**** {alloc_group} REPLENISH {} -> {} ****",
+                reserve_count,
+                cfg.reserve
+            );
+            create_bridges(
+                alloc_group,
+                cfg,
+                provider,
+                ((cfg.reserve as i64) - reserve_count).min(64) as usize,
+            )
+            .await;
+        }
+
+        anyhow::Ok(())
     }
+    .timeout(Duration::from_secs(3600))
+    .await
+    .ok_or_else(|| anyhow::anyhow!("timeout"))?
+}
 
-    let (reserve_count,): (i64,) = sqlx::query_as(
-        "select count(bridge_id) from bridges where status = 'reserve' and alloc_group = $1",
-    )
-    .bind(alloc_group)
-    .fetch_one(DATABASE.deref())
-    .await?;
-    if reserve_count < cfg.reserve as i64 {
-        log::debug!("**** {alloc_group} REPLENISH {} -> {} ****", reserve_count, cfg.reserve);
-        let mut tasks = FuturesUnordered::new();
-        for _ in 0..((cfg.reserve as i64) - reserve_count).min(64) {
-            tasks.push(async  {
-
-            let created = provider.create_server().await.context("cannot create more")?;
-            let remote_alloc_group = cfg.override_group.as_deref().unwrap_or(alloc_group);
-            // set into reserve status
-            let bridge_secret = &CONFIG.bridge_secret;
-            let cachebust = rand::thread_rng().gen::<u64>();
-            if cfg.services.contains(&Service::Geph4) {
-                ssh_execute(&created.ip_addr, &format!("wget -qO- {}?cachebust={cachebust} | env AGROUP={remote_alloc_group} BSECRET={bridge_secret} sh", GEPH4_GIST)).await?;
-            }
-            if cfg.services.contains(&Service::Geph5) {
-                ssh_execute(&created.ip_addr, &format!("wget -qO- {}?cachebust={cachebust} | env AGROUP={remote_alloc_group} BSECRET={bridge_secret} sh", GEPH5_GIST)).await?;
-            }
-            if cfg.services.contains(&Service::Earendil) {
-                ssh_execute(&created.ip_addr, &format!("wget -qO- {}?cachebust={cachebust} | env AGROUP={remote_alloc_group} BSECRET={bridge_secret} sh", EARENDIL_GIST)).await?;
+/// Drains a removed group once its frontline is gone, returning whether it did. Users keep the frontline bridges they were handed until prune retires them, since nothing promotes new ones in a removed group.
+async fn wind_down(alloc_group: &str, provider: &dyn Provider) -> anyhow::Result<bool> {
+    let frontline = DATABASE
+        .status_counts(alloc_group)
+        .await?
+        .get("frontline")
+        .copied()
+        .unwrap_or_default();
+    if frontline > 0 {
+        log::debug!(
+            "{alloc_group} is removed, waiting for its {frontline} frontline bridges to age out"
+        );
+        return Ok(false);
+    }
+    log::info!("***** draining removed group {alloc_group} *****");
+    drain(alloc_group).await?;
+    sweep_orphans(provider).await?;
+    Ok(true)
+}
+
+/// Forgets every bridge in the group, so that the next sweep destroys their servers, returning how many there were.
+pub async fn drain(alloc_group: &str) -> anyhow::Result<usize> {
+    let bridges: Vec<_> = DATABASE
+        .all_bridges()
+        .await?
+        .into_iter()
+        .filter(|b| b.alloc_group == alloc_group)
+        .collect();
+    for bridge in bridges.iter() {
+        DATABASE.delete_bridge(&bridge.bridge_id).await?;
+    }
+    Ok(bridges.len())
+}
+
+/// Destroys every server of the provider that phalanx created but no longer has in the database.
+pub async fn sweep_orphans(provider: &dyn Provider) -> anyhow::Result<()> {
+    let bridges = DATABASE.all_bridges().await?;
+    provider
+        .retain_by_id(Box::new(move |id| {
+            bridges.iter().any(|b| b.bridge_id == id)
+        }))
+        .await
+}
+
+/// Destroys the untagged servers that versions before owner tags created, unless the database still knows their bridges.
+pub async fn sweep_legacy_orphans(provider: &dyn Provider) -> anyhow::Result<()> {
+    let bridges = DATABASE.all_bridges().await?;
+    provider
+        .retain_legacy_by_id(Box::new(move |id| {
+            bridges.iter().any(|b| b.bridge_id == id)
+        }))
+        .await
+}
+
+/// Creates and provisions this many new bridges in the group at once, returning how many made it into the reserve.
+pub async fn create_bridges(
+    alloc_group: &str,
+    cfg: &GroupConfig,
+    provider: &dyn Provider,
+    count: usize,
+) -> usize {
+    if dry_run() {
+        plan(
+            &format!("provision {alloc_group}"),
+            format!("would create {count} bridges"),
+        );
+        return 0;
+    }
+    let mut tasks = FuturesUnordered::new();
+    for _ in 0..count {
+        tasks.push(async {
+            let start = Instant::now();
+            let created = provider
+                .create_server()
+                .await
+                .context("cannot create more")?;
+            observe_stage(alloc_group, "create", start);
+            // claimed before it shows up in the database, so that it is never resumed while still being provisioned here
+            let _claim = Provisioning::claim(&created.id);
+            RESUMABLE.insert(created.id.clone());
+            // record it right away, so that a crash from here on does not leave the server unaccounted for
+            DATABASE
+                .insert_bridge(&created.id, &created.ip_addr, alloc_group, "creating")
+                .await?;
+            provision(
+                alloc_group,
+                cfg,
+                provider,
+                &created.id,
+                &created.ip_addr,
+                "creating",
+            )
+            .await
+        });
+    }
+    // let every task run to completion, since dropping one midway would strand its bridge
+    let mut created = 0;
+    while let Some(next) = tasks.next().await {
+        match next {
+            Ok(()) => created += 1,
+            Err(err) => log::warn!("{alloc_group}: provisioning failed: {:?}", err),
+        }
+    }
+    created
+}
+
+/// Takes on the bridges that an earlier run left halfway through provisioning, which are whatever is unfinished on startup.
+async fn adopt_unfinished(alloc_group: &str) -> anyhow::Result<()> {
+    for status in ["creating", "installing"] {
+        for bridge in DATABASE.group_bridges(alloc_group, status).await? {
+            RESUMABLE.insert(bridge.bridge_id);
+        }
+    }
+    Ok(())
+}
+
+/// Takes on the bridges that have been unfinished for longer than anyone could still be provisioning them, unless this process is.
+async fn adopt_abandoned(alloc_group: &str) -> anyhow::Result<()> {
+    for status in ["creating", "installing"] {
+        for bridge in DATABASE
+            .stale_bridges(alloc_group, status, ABANDONED_AFTER.as_secs() as i64)
+            .await?
+        {
+            if !PROVISIONING.contains(&bridge.bridge_id) && RESUMABLE.insert(bridge.bridge_id) {
+                log::warn!(
+                    "{alloc_group}: {} was left in {status}, taking it on",
+                    bridge.ip_addr
+                );
             }
-            if cfg.services.contains(&Service::Geph5Exit) {
-                // Run the original setup script first
-                ssh_execute(&created.ip_addr, &format!("wget -q -O /tmp/script.sh {} && AUTH_TOKEN=fc9d0d668165135a18f6fa42c82a7971c43b7d07 bash /tmp/script.sh", GEPH5_EXIT_SCRIPT)).await?;
-                
-                // After the script has run, override the country, city, and total_ratelimit if specified
-                if cfg.exit_country.is_some() || cfg.exit_city.is_some() || cfg.exit_total_ratelimit.is_some() {
-                    // Create a script to update the config file
-                    let update_config_commands = format!(
-                        r#"
-                        # Backup the original config
-                        cp /etc/geph5-exit/config.yaml /etc/geph5-exit/config.yaml.orig
-                        
-                        # Update the config file with overridden values
-                        {}
-                        {}
-                        {}
-                        
-                        # Restart the service to apply changes
-                        systemctl restart geph5-exit
-                        "#,
-                        cfg.exit_country.as_ref().map_or(String::new(), |country| 
-                            format!("sed -i 's/^country: .*/country: {}/' /etc/geph5-exit/config.yaml", country)),
-                        cfg.exit_city.as_ref().map_or(String::new(), |city| 
-                            format!("sed -i 's/^city: .*/city: {}/' /etc/geph5-exit/config.yaml", city)),
-                        cfg.exit_total_ratelimit.as_ref().map_or(String::new(), |limit| 
-                            format!("echo 'total_ratelimit: {}' >> /etc/geph5-exit/config.yaml", limit))
-                    );
-                    
-                    // Execute the config update script
-                    ssh_execute(&created.ip_addr, &format!("bash -c \"{}\"", update_config_commands)).await?;
-                }
+        }
+    }
+    Ok(())
+}
+
+/// Picks up the bridges in the group that an earlier run, or an iteration that timed out, left halfway through provisioning.
+async fn resume_unfinished(
+    alloc_group: &str,
+    cfg: &GroupConfig,
+    provider: &dyn Provider,
+) -> anyhow::Result<()> {
+    let mut tasks = FuturesUnordered::new();
+    for status in ["creating", "installing"] {
+        for bridge in DATABASE.group_bridges(alloc_group, status).await? {
+            if !RESUMABLE.contains(&bridge.bridge_id) {
+                continue;
             }
-            if let Some(max_bandwidth_gb) = cfg.max_bandwidth_gb {
-                ssh_execute(&created.ip_addr, &format!("wget -qO- {}?cachebust={cachebust} | env TRAFFIC_LIMIT_GB={max_bandwidth_gb} sh", LIMIT_BANDWIDTH_GIST)).await?;
+            let Some(claim) = Provisioning::claim(&bridge.bridge_id) else {
+                continue;
+            };
+            if dry_run() {
+                plan(
+                    &format!("provision {alloc_group} {}", bridge.bridge_id),
+                    format!("would resume {} from {status}", bridge.ip_addr),
+                );
+                continue;
             }
-            // ssh_execute(&addr, &format!("shutdown -h +{}", (cfg.max_lifetime_hr / 60.0) as u64)).await?;
-            sqlx::query("insert into bridges (bridge_id, ip_addr, alloc_group, status, change_time) values ($1, $2, $3, $4, NOW())").bind(created.id).bind(created.ip_addr).bind(alloc_group).bind("reserve").execute(DATABASE.deref()).await?;
-            anyhow::Ok(())
+            log::info!(
+                "{alloc_group}: resuming {} ({}) from {status}",
+                bridge.bridge_id,
+                bridge.ip_addr
+            );
+            tasks.push(async move {
+                let _claim = claim;
+                provision(
+                    alloc_group,
+                    cfg,
+                    provider,
+                    &bridge.bridge_id,
+                    &bridge.ip_addr,
+                    status,
+                )
+                .await
             });
         }
-        while let Some(next) = tasks.next().await {
-            next?;
+    }
+    while let Some(next) = tasks.next().await {
+        if let Err(err) = next {
+            log::warn!("{alloc_group}: resuming failed: {:?}", err)
+        }
+    }
+    Ok(())
+}
+
+/// Carries a bridge from `creating` or `installing` through to `reserve`, tearing it down if any step fails.
+async fn provision(
+    alloc_group: &str,
+    cfg: &GroupConfig,
+    provider: &dyn Provider,
+    bridge_id: &str,
+    ip_addr: &str,
+    status: &str,
+) -> anyhow::Result<()> {
+    let result = async {
+        // a bridge that got as far as `installing` already has its host key pinned, and must keep presenting it
+        if status == "creating" {
+            let start = Instant::now();
+            pin_host_key(ip_addr).await.context("cannot pin host key")?;
+            observe_stage(alloc_group, "reachable", start);
+            DATABASE.set_status(bridge_id, "installing").await?;
+        }
+        let start = Instant::now();
+        install(alloc_group, cfg, ip_addr).await?;
+        observe_stage(alloc_group, "install", start);
+        anyhow::Ok(())
+    }
+    // bounded, so that other processes can tell when a bridge was abandoned
+    .timeout(PROVISION_TIMEOUT)
+    .await
+    .unwrap_or_else(|| Err(anyhow::anyhow!("provisioning timed out")));
+    if let Err(err) = result {
+        log::warn!("{alloc_group}: provisioning {ip_addr} failed, tearing it down");
+        forget_bootstrap_host_keys(ip_addr);
+        DATABASE.delete_bridge(bridge_id).await?;
+        let failed_id = bridge_id.to_string();
+        provider
+            .retain_by_id(Box::new(move |id| id != failed_id))
+            .await?;
+        RESUMABLE.remove(bridge_id);
+        return Err(err);
+    }
+    DATABASE.set_status(bridge_id, "reserve").await?;
+    RESUMABLE.remove(bridge_id);
+    Ok(())
+}
+
+/// Deploys the group's services onto a server, failing if any step does. Every step can safely be run again.
+async fn install(alloc_group: &str, cfg: &GroupConfig, ip_addr: &str) -> anyhow::Result<()> {
+    let remote_alloc_group = cfg.override_group.as_deref().unwrap_or(alloc_group);
+    // set into reserve status
+    let bridge_secret = &CONFIG.bridge_secret;
+    let cachebust = rand::thread_rng().gen::<u64>();
+    if cfg.services.contains(&Service::Geph4) {
+        run_script(ip_addr, &format!("wget -qO- {}?cachebust={cachebust} | env AGROUP={remote_alloc_group} BSECRET={bridge_secret} sh", GEPH4_GIST)).await?;
+    }
+    if cfg.services.contains(&Service::Geph5) {
+        run_script(ip_addr, &format!("wget -qO- {}?cachebust={cachebust} | env AGROUP={remote_alloc_group} BSECRET={bridge_secret} sh", GEPH5_GIST)).await?;
+    }
+    if cfg.services.contains(&Service::Earendil) {
+        run_script(ip_addr, &format!("wget -qO- {}?cachebust={cachebust} | env AGROUP={remote_alloc_group} BSECRET={bridge_secret} sh", EARENDIL_GIST)).await?;
+    }
+    if cfg.services.contains(&Service::Geph5Exit) {
+        // Run the original setup script first
+        run_script(ip_addr, &format!("wget -q -O /tmp/script.sh {} && AUTH_TOKEN=fc9d0d668165135a18f6fa42c82a7971c43b7d07 bash /tmp/script.sh", GEPH5_EXIT_SCRIPT)).await?;
+
+        // After the script has run, override the country, city, and total_ratelimit if specified
+        if cfg.exit_country.is_some()
+            || cfg.exit_city.is_some()
+            || cfg.exit_total_ratelimit.is_some()
+        {
+            // Create a script to update the config file
+            let update_config_commands = format!(
+                r#"
+                # Backup the original config
+                cp /etc/geph5-exit/config.yaml /etc/geph5-exit/config.yaml.orig
+
+                # Update the config file with overridden values
+                {}
+                {}
+                {}
+
+                # Restart the service to apply changes
+                systemctl restart geph5-exit
+                "#,
+                cfg.exit_country
+                    .as_ref()
+                    .map_or(String::new(), |country| format!(
+                        "sed -i 's/^country: .*/country: {}/' /etc/geph5-exit/config.yaml",
+                        country
+                    )),
+                cfg.exit_city.as_ref().map_or(String::new(), |city| format!(
+                    "sed -i 's/^city: .*/city: {}/' /etc/geph5-exit/config.yaml",
+                    city
+                )),
+                cfg.exit_total_ratelimit
+                    .as_ref()
+                    .map_or(String::new(), |limit| format!(
+                        "echo 'total_ratelimit: {}' >> /etc/geph5-exit/config.yaml",
+                        limit
+                    ))
+            );
+
+            // Execute the config update script
+            run_script(ip_addr, &format!("bash -c \"{}\"", update_config_commands)).await?;
         }
     }
+    if let Some(max_bandwidth_gb) = cfg.max_bandwidth_gb {
+        run_script(
+            ip_addr,
+            &format!(
+                "wget -qO- {}?cachebust={cachebust} | env TRAFFIC_LIMIT_GB={max_bandwidth_gb} sh",
+                LIMIT_BANDWIDTH_GIST
+            ),
+        )
+        .await?;
+    }
+    // ssh_execute(&addr, &format!("shutdown -h +{}", (cfg.max_lifetime_hr / 60.0) as u64)).await?;
+    for service in cfg.services.iter() {
+        verify_service(ip_addr, service).await?;
+    }
+    Ok(())
+}
 
-    anyhow::Ok(())
-}.timeout(Duration::from_secs(3600)).await.ok_or_else(|| anyhow::anyhow!("timeout"))?
+/// Runs a deployment script, failing if it exits with a non-zero status.
+async fn run_script(ip_addr: &str, cmd: &str) -> anyhow::Result<()> {
+    let output = ssh_execute(ip_addr, cmd, INSTALL_TIMEOUT).await?;
+    if !output.success() {
+        anyhow::bail!(
+            "script exited with status {} on {ip_addr}: {}",
+            output.exit_status,
+            output.stderr.trim()
+        )
+    }
+    Ok(())
 }
 
-fn new_id() -> String {
-    format!(
-        "{}-{}-{}-{}-{}",
-        eff_wordlist::large::random_word(),
-        eff_wordlist::large::random_word(),
-        eff_wordlist::large::random_word(),
-        eff_wordlist::large::random_word(),
-        eff_wordlist::large::random_word()
+/// Checks that a service's systemd unit exists, runs, and listens on some port.
+async fn verify_service(ip_addr: &str, service: &Service) -> anyhow::Result<()> {
+    let unit = service.systemd_unit();
+    let output = ssh_execute(
+        ip_addr,
+        &format!(
+            r#"systemctl cat {unit} > /dev/null || {{ echo "{unit} is not installed" >&2; exit 1; }}
+systemctl is-active --quiet {unit} || systemctl start {unit} || {{ echo "{unit} does not start" >&2; exit 1; }}
+for i in $(seq 30); do
+    ss -Hlntup | grep -q "pid=$(systemctl show -p MainPID --value {unit})," && exit 0
+    sleep 1
+done
+echo "{unit} is not listening on any port" >&2
+exit 1"#
+        ),
+        Duration::from_secs(60),
     )
-}
\ No newline at end of file
+    .await?;
+    if !output.success() {
+        anyhow::bail!("verification failed on {ip_addr}: {}", output.stderr.trim())
+    }
+    Ok(())
+}
@@ -1,60 +1,71 @@
-use std::{ops::Deref, time::Duration};
-
-use futures_util::future::join_all;
+use std::time::Duration;
 
 use crate::{
-    config::{GroupConfig, CONFIG},
+    config::{group_config, CONFIG},
     database::DATABASE,
+    metrics::PRUNED,
 };
 
+/// Deletes the bridges that are compromised or throttled in every group, and the blocked ones once they had their chance to be unblocked.
 pub async fn loop_prune() {
-    let _all = smol::spawn(loop_prune_all());
-    join_all(
-        CONFIG
-            .groups
-            .iter()
-            .map(|(gname, gcfg)| loop_prune_for_group(gname, gcfg)),
-    )
-    .await;
-}
-
-async fn loop_prune_all() {
     loop {
-        if let Err(err) = sqlx::query("delete from bridges where status = 'blocked'")
-            .execute(DATABASE.deref())
-            .await
-        {
-            log::warn!("prune_all error: {:?}", err);
+        // a blocked bridge can only be unblocked by rounds of probing within the window, so it is kept until then
+        let keep_blocked = CONFIG.gfw.window_mins as i64 * 60;
+        for (status, keep_secs) in [
+            ("blocked", keep_blocked),
+            ("compromised", 0),
+            ("throttled", 0),
+        ] {
+            match DATABASE.delete_with_status(status, keep_secs).await {
+                Ok(deleted) => PRUNED.with_label_values(&[status]).inc_by(deleted),
+                Err(err) => log::warn!("prune_all error: {:?}", err),
+            }
         }
         smol::Timer::after(Duration::from_secs(1)).await;
     }
 }
 
-async fn loop_prune_for_group(group_name: &str, group_config: &GroupConfig) {
+/// Deletes the slowest bridge of the group every so often, so that bridges live for about the configured lifetime on average. Once the group is removed, its frontline keeps aging out at the same pace.
+pub async fn loop_prune_group(group_name: String) {
+    let mut last_cfg = None;
     loop {
-        let (total_group_count,): (i64,) =
-            sqlx::query_as("select count(*) from bridges where alloc_group = $1")
-                .bind(group_name)
-                .fetch_one(DATABASE.deref())
-                .await
-                .unwrap();
-        let delete_interval =
-            group_config.avg_lifetime_hr / (total_group_count.max(1) as f64) * 3600.0;
+        if let Some(cfg) = group_config(&group_name) {
+            last_cfg = Some(cfg);
+        }
+        let Some(cfg) = &last_cfg else {
+            smol::Timer::after(Duration::from_secs(60)).await;
+            continue;
+        };
+        let total_group_count: i64 = DATABASE
+            .status_counts(&group_name)
+            .await
+            .unwrap()
+            .values()
+            .sum();
+        let delete_interval = cfg.avg_lifetime_hr / (total_group_count.max(1) as f64) * 3600.0;
         smol::Timer::after(Duration::from_secs_f64(delete_interval)).await;
         log::debug!("prune timer fires for {group_name} with delete_interval {delete_interval}");
-        if let Err(err) = sqlx::query(
-            "delete from bridges where alloc_group = $1 and last_mbps = (
-        SELECT MIN(last_mbps)
-        FROM bridges
-        WHERE alloc_group = $1
-        AND last_mbps > 1
-      )",
-        )
-        .bind(group_name)
-        .execute(DATABASE.deref())
-        .await
-        {
-            log::warn!("prune error for {group_name}: {:?}", err);
+        let pruned = if group_config(&group_name).is_some() {
+            DATABASE.delete_slowest(&group_name).await
+        } else {
+            retire_frontline(&group_name).await
+        };
+        match pruned {
+            Ok(deleted) => PRUNED.with_label_values(&["slowest"]).inc_by(deleted),
+            Err(err) => log::warn!("prune error for {group_name}: {:?}", err),
         }
     }
 }
+
+/// Deletes the slowest frontline bridge of a removed group, which may have gone unmeasured since its frontline loop stopped.
+async fn retire_frontline(group_name: &str) -> anyhow::Result<u64> {
+    let frontline = DATABASE.group_bridges(group_name, "frontline").await?;
+    let Some(slowest) = frontline
+        .iter()
+        .min_by(|a, b| a.last_mbps.total_cmp(&b.last_mbps))
+    else {
+        return Ok(0);
+    };
+    DATABASE.delete_bridge(&slowest.bridge_id).await?;
+    Ok(1)
+}
@@ -0,0 +1,70 @@
+use std::{collections::BTreeSet, path::Path, time::Duration, time::SystemTime};
+
+use crate::{
+    cli::ARGS,
+    config::{load_config, replace_group_configs, Config, CONFIG},
+};
+
+/// Reloads the config whenever its file changes. The loops of existing groups pick up their new configs by themselves, providers included, and those of removed groups wind them down, so only new groups need their loops started.
+pub async fn loop_reload(start_group: impl Fn(&str)) {
+    let path = &ARGS.config;
+    let mut started: BTreeSet<String> = CONFIG.groups.keys().cloned().collect();
+    let mut last_modified = modified(path);
+    loop {
+        smol::Timer::after(Duration::from_secs(10)).await;
+        let modified = modified(path);
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        let config = match load_config(path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!(
+                    "keeping the old config, since the new one is invalid: {:#}",
+                    err
+                );
+                continue;
+            }
+        };
+        if without_groups(&config) != without_groups(&CONFIG) {
+            log::warn!("only changes to groups take effect without a restart");
+        }
+        let old_groups = replace_group_configs(config.groups.clone());
+        for (group, group_cfg) in config.groups.iter() {
+            match old_groups.get(group) {
+                Some(old) => {
+                    if yaml(old) != yaml(group_cfg) {
+                        log::info!("reloaded the config of {group}");
+                    }
+                }
+                None => log::info!("added group {group}"),
+            }
+            // a group that was removed and added back still has its loops
+            if started.insert(group.clone()) {
+                start_group(group);
+            }
+        }
+        for group in old_groups.keys() {
+            if !config.groups.contains_key(group) {
+                log::info!("removed group {group}, which winds down from now on");
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn without_groups(config: &Config) -> serde_yaml::Value {
+    yaml(&Config {
+        groups: Default::default(),
+        ..config.clone()
+    })
+}
+
+fn yaml(value: &impl serde::Serialize) -> serde_yaml::Value {
+    serde_yaml::to_value(value).unwrap_or_default()
+}
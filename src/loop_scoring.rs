@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+use crate::{analytics::group_stats, config::group_configs};
+
+/// Providers need this many bridges through the frontline before their survival counts for anything.
+const MIN_FRONTLINED: usize = 5;
+
+/// How much longer than average the bridges of every provider survive, by group and provider.
+static SCORES: Lazy<DashMap<(String, String), f64>> = Lazy::new(DashMap::new);
+
+/// The factor to weight new creations on this provider by. Providers without enough history count as average.
+pub fn survival_score(group: &str, provider: &str) -> f64 {
+    SCORES
+        .get(&(group.to_string(), provider.to_string()))
+        .map_or(1.0, |s| *s)
+}
+
+/// Rescores the providers of every group that weights creations by survival, every so often.
+pub async fn loop_scoring() {
+    loop {
+        for (group, cfg) in group_configs() {
+            if !cfg.weight_by_survival {
+                continue;
+            }
+            if let Err(err) = rescore(&group).await {
+                log::warn!("could not score the providers of {group}: {:?}", err)
+            }
+        }
+        smol::Timer::after(Duration::from_secs(600)).await;
+    }
+}
+
+/// Scores each provider by its median survival relative to the group's average, within 0.1 to 10.
+pub async fn rescore(group: &str) -> anyhow::Result<()> {
+    let survivals: Vec<(String, f64)> = group_stats(group)
+        .await?
+        .into_iter()
+        .filter(|(_, stats)| stats.frontlined >= MIN_FRONTLINED)
+        .filter_map(|(provider, stats)| Some((provider, stats.median_survival_hr?)))
+        .collect();
+    let average = survivals.iter().map(|(_, hr)| hr).sum::<f64>() / survivals.len() as f64;
+    SCORES.retain(|(g, _), _| g != group);
+    for (provider, hr) in survivals {
+        let score = if average > 0.0 {
+            (hr / average).clamp(0.1, 10.0)
+        } else {
+            1.0
+        };
+        log::debug!("{group}: {provider} bridges survive {hr:.1} hours, scoring {score:.2}");
+        SCORES.insert((group.to_string(), provider), score);
+    }
+    Ok(())
+}
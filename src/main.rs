@@ -1,56 +1,197 @@
+use admin::serve_admin;
 use async_compat::{Compat, CompatExt};
-use config::{ProviderConfig, CONFIG};
+use cli::{run_command, Command, ARGS};
+use config::{group_config, load_config, GroupConfig, ProviderConfig, CONFIG};
+use dashmap::DashSet;
+use database::DATABASE;
 use loop_frontline::loop_frontline;
 use loop_gfw::loop_gfw;
+use loop_lightsail::loop_lightsail;
 use loop_onoff::loop_onoff;
 use loop_provision::loop_provision;
-use loop_prune::loop_prune;
+use loop_prune::{loop_prune, loop_prune_group};
+use loop_reload::loop_reload;
+use loop_scoring::loop_scoring;
+use once_cell::sync::Lazy;
 use provider::{
-    hetzner::HetznerProvider, ip_fresher::IpFresher, lightsail::LightsailProvider,
-    linode::LinodeProvider, oneprovider::OneCloudProvider, ovh::OvhProvider,
-    scaleway::ScalewayProvider, serverspace::ServerSpaceProvider, vultr::VultrProvider, Provider,
+    digitalocean::DigitalOceanProvider,
+    ec2::Ec2Provider,
+    failover::{Failover, Member},
+    hetzner::HetznerProvider,
+    ip_fresher::IpFresher,
+    lightsail::LightsailProvider,
+    linode::LinodeProvider,
+    oneprovider::OneCloudProvider,
+    openstack::OpenStackProvider,
+    ovh::OvhProvider,
+    reloading::Reloading,
+    scaleway::ScalewayProvider,
+    serverspace::ServerSpaceProvider,
+    throttle::Throttled,
+    vultr::VultrProvider,
+    Provider,
 };
 use std::sync::Arc;
 
+mod admin;
+mod analytics;
+mod cli;
 mod config;
 mod database;
+mod detector;
+#[cfg(test)]
+mod harness;
 mod id;
 mod loop_frontline;
 mod loop_gfw;
+mod loop_lightsail;
 mod loop_onoff;
 mod loop_provision;
 mod loop_prune;
+mod loop_reload;
+mod loop_scoring;
+mod metrics;
+mod plan;
 mod provider;
 mod ssh;
 
 fn main() {
     env_logger::init();
+    // loading CONFIG can only panic, so report whatever is wrong with the config first
+    if let Err(err) = load_config(&ARGS.config) {
+        eprintln!("error: {:#}", err);
+        std::process::exit(1);
+    }
     smol::block_on(Compat::new(async {
-        smol::spawn(loop_onoff().compat()).detach();
-        smol::spawn(loop_gfw().compat()).detach();
-        smol::spawn(loop_prune().compat()).detach();
-
-        // for every provider, start the right loops
-        for (group, group_cfg) in CONFIG.groups.iter() {
-            let provider: Arc<dyn Provider> = match &group_cfg.provider {
-                ProviderConfig::Lightsail(cfg) => Arc::new(LightsailProvider::new(cfg.clone())),
-                ProviderConfig::Vultr(cfg) => Arc::new(VultrProvider::new(cfg.clone())),
-                ProviderConfig::Scaleway(cfg) => Arc::new(ScalewayProvider::new(cfg.clone())),
-                ProviderConfig::Hetzner(cfg) => Arc::new(HetznerProvider::new(cfg.clone())),
-                ProviderConfig::Ovh(cfg) => Arc::new(OvhProvider::new(cfg.clone())),
-                ProviderConfig::Onecloud(cfg) => Arc::new(OneCloudProvider::new(cfg.clone())),
-                ProviderConfig::Linode(cfg) => {
-                    Arc::new(IpFresher::new(LinodeProvider::new(cfg.clone())))
+        match &ARGS.command {
+            None | Some(Command::Run(_)) => run().await,
+            Some(command) => {
+                if !matches!(command, Command::ValidateConfig(_)) {
+                    DATABASE
+                        .migrate()
+                        .await
+                        .expect("could not migrate the database");
                 }
-                ProviderConfig::ServerSpace(cfg) => Arc::new(ServerSpaceProvider::new(cfg.clone())),
-            };
-            smol::spawn(
-                loop_provision(group.to_string(), group_cfg.clone(), provider.clone()).compat(),
-            )
-            .detach();
-            smol::spawn(loop_frontline(group.to_string(), group_cfg.clone()).compat()).detach();
+                if let Err(err) = run_command(command).await {
+                    eprintln!("error: {:#}", err);
+                    std::process::exit(1);
+                }
+            }
         }
-
-        smol::future::pending().await
     }))
 }
+
+/// Runs the daemon, forever.
+async fn run() {
+    if plan::dry_run() {
+        println!("DRY RUN: printing plans instead of creating, changing or deleting anything");
+    }
+    DATABASE
+        .migrate()
+        .await
+        .expect("could not migrate the database");
+    smol::spawn(loop_onoff().compat()).detach();
+    smol::spawn(loop_gfw().compat()).detach();
+    smol::spawn(loop_prune().compat()).detach();
+    smol::spawn(loop_scoring().compat()).detach();
+    if let Some(admin) = CONFIG.admin.clone() {
+        smol::spawn(
+            async move {
+                if let Err(err) = serve_admin(admin).await {
+                    log::error!("admin API died: {:?}", err)
+                }
+            }
+            .compat(),
+        )
+        .detach();
+    }
+
+    for group in CONFIG.groups.keys() {
+        start_group(group);
+    }
+    loop_reload(start_group).await
+}
+
+/// Starts the loops of a group, which keep running with whatever config the group has from then on.
+fn start_group(group: &str) {
+    let Some(group_cfg) = group_config(group) else {
+        return;
+    };
+    // rebuilt whenever a reload changes the providers, starting the right loops for them
+    let provider = Arc::new(Reloading::new(group, &group_cfg, {
+        let group = group.to_string();
+        move |cfg| {
+            start_lightsail(&group, cfg);
+            group_provider(&group, cfg)
+        }
+    }));
+    smol::spawn(loop_provision(group.to_string(), provider).compat()).detach();
+    smol::spawn(loop_frontline(group.to_string()).compat()).detach();
+    smol::spawn(loop_prune_group(group.to_string()).compat()).detach();
+}
+
+/// Starts watching the Lightsail providers of a group that nothing watches yet.
+fn start_lightsail(group: &str, group_cfg: &GroupConfig) {
+    static WATCHED: Lazy<DashSet<(String, String)>> = Lazy::new(DashSet::new);
+    for (_, choice) in group_cfg.provider_choices() {
+        if let ProviderConfig::Lightsail(cfg) = choice.provider {
+            let key = serde_yaml::to_string(&cfg).unwrap_or_default();
+            if WATCHED.insert((group.to_string(), key)) {
+                smol::spawn(loop_lightsail(group.to_string(), cfg).compat()).detach();
+            }
+        }
+    }
+}
+
+/// All the providers of a group, throttled and failing over to each other.
+fn group_provider(group: &str, group_cfg: &GroupConfig) -> Arc<dyn Provider> {
+    let members = group_cfg
+        .provider_choices()
+        .into_iter()
+        .map(|(name, choice)| {
+            let throttled = Arc::new(Throttled::new(
+                group,
+                &name,
+                &choice.provider.account(),
+                group_cfg.create_limit.clone(),
+                make_provider(&choice.provider),
+            ));
+            Member {
+                provider: fresh_provider(throttled, &choice.provider, group_cfg),
+                name,
+                weight: choice.weight,
+                priority: choice.priority,
+            }
+        })
+        .collect();
+    Arc::new(Failover::new(group, members))
+}
+
+/// The throttled provider, rejecting stale IPs if the group wants fresh ones. The throttle goes inside, so that every server created and rejected spends a token.
+fn fresh_provider(
+    provider: Arc<dyn Provider>,
+    cfg: &ProviderConfig,
+    group_cfg: &GroupConfig,
+) -> Arc<dyn Provider> {
+    if group_cfg.wants_fresh_ips(cfg) {
+        Arc::new(IpFresher::new(provider, group_cfg.ip_freshness.clone()))
+    } else {
+        provider
+    }
+}
+
+fn make_provider(cfg: &ProviderConfig) -> Arc<dyn Provider> {
+    match cfg {
+        ProviderConfig::Lightsail(cfg) => Arc::new(LightsailProvider::new(cfg.clone())),
+        ProviderConfig::Vultr(cfg) => Arc::new(VultrProvider::new(cfg.clone())),
+        ProviderConfig::Scaleway(cfg) => Arc::new(ScalewayProvider::new(cfg.clone())),
+        ProviderConfig::Hetzner(cfg) => Arc::new(HetznerProvider::new(cfg.clone())),
+        ProviderConfig::Ovh(cfg) => Arc::new(OvhProvider::new(cfg.clone())),
+        ProviderConfig::Onecloud(cfg) => Arc::new(OneCloudProvider::new(cfg.clone())),
+        ProviderConfig::Linode(cfg) => Arc::new(LinodeProvider::new(cfg.clone())),
+        ProviderConfig::ServerSpace(cfg) => Arc::new(ServerSpaceProvider::new(cfg.clone())),
+        ProviderConfig::DigitalOcean(cfg) => Arc::new(DigitalOceanProvider::new(cfg.clone())),
+        ProviderConfig::Ec2(cfg) => Arc::new(Ec2Provider::new(cfg.clone())),
+        ProviderConfig::Openstack(cfg) => Arc::new(OpenStackProvider::new(cfg.clone())),
+    }
+}
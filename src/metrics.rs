@@ -0,0 +1,138 @@
+use std::{collections::BTreeMap, time::Instant};
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_gauge_vec, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge_vec, Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec,
+    TextEncoder,
+};
+
+/// The statuses a bridge goes through, which are reported even when no bridge has them.
+const STATUSES: &[&str] = &[
+    "creating",
+    "installing",
+    "reserve",
+    "frontline",
+    "blocked",
+    "compromised",
+    "throttled",
+];
+
+/// Bridges per group and status, refreshed by the provisioning loop of each group.
+pub static BRIDGES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "phalanx_bridges",
+        "Number of bridges, by group and status",
+        &["group", "status"]
+    )
+    .unwrap()
+});
+
+pub static OVERLOAD: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "phalanx_overload",
+        "Average throughput of the frontline over the target throughput",
+        &["group"]
+    )
+    .unwrap()
+});
+
+pub static DELAY_MS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "phalanx_delay_ms",
+        "Artificial delay imposed on clients of an overloaded group",
+        &["group"]
+    )
+    .unwrap()
+});
+
+pub static SIGNAL_MBPS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "phalanx_signal_mbps",
+        "Average throughput last measured on the frontline",
+        &["group"]
+    )
+    .unwrap()
+});
+
+pub static SERVERS_CREATED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "phalanx_create_server_total",
+        "Calls to create a server, by outcome",
+        &["group", "provider", "result"]
+    )
+    .unwrap()
+});
+
+pub static GFW_BLOCKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "phalanx_gfw_blocks_total",
+        "Bridges found to be blocked by the GFW",
+        &["group"]
+    )
+    .unwrap()
+});
+
+pub static PRUNED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "phalanx_pruned_total",
+        "Bridges pruned, by reason",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static PROVIDER_HEALTHY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "phalanx_provider_healthy",
+        "Whether a provider of a group is accepting creates, as judged by its circuit breaker",
+        &["group", "provider"]
+    )
+    .unwrap()
+});
+
+pub static SSH_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "phalanx_ssh_failures_total",
+        "SSH commands that failed or timed out",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+/// Seconds spent in each provisioning stage: `create` until the provider returns the server, `reachable` until its host key is pinned, and `install` until its services are verified.
+pub static PROVISION_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "phalanx_provision_seconds",
+        "Time spent in each stage of provisioning a bridge",
+        &["group", "stage"],
+        exponential_buckets(1.0, 2.0, 13).unwrap()
+    )
+    .unwrap()
+});
+
+/// Records how long a provisioning stage took since it started.
+pub fn observe_stage(group: &str, stage: &str, start: Instant) {
+    PROVISION_SECONDS
+        .with_label_values(&[group, stage])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+/// Sets the bridge counts of a group, by status, zeroing the statuses it has no bridges in.
+pub fn set_bridge_counts(group: &str, counts: &BTreeMap<String, i64>) {
+    for status in STATUSES {
+        if !counts.contains_key(*status) {
+            BRIDGES.with_label_values(&[group, status]).set(0);
+        }
+    }
+    for (status, count) in counts {
+        BRIDGES.with_label_values(&[group, status]).set(*count);
+    }
+}
+
+/// Renders every metric in the Prometheus text format.
+pub fn render() -> anyhow::Result<String> {
+    let mut buf = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+use crate::{
+    config::CONFIG,
+    database::{BridgeInfo, BridgeStore, Lifecycle, ProbeRecord},
+};
+
+/// Whether this is a dry run, where the loops work out their decisions but create, change and delete nothing.
+pub fn dry_run() -> bool {
+    CONFIG.dry_run
+}
+
+/// The last decision printed about every topic.
+static LAST_DECISIONS: Lazy<DashMap<String, String>> = Lazy::new(DashMap::new);
+
+/// Prints what a loop would do about something on a dry run. The loops keep coming back to the same decisions, so each one is only printed when it changes.
+pub fn plan(topic: &str, decision: String) {
+    if LAST_DECISIONS
+        .insert(topic.to_string(), decision.clone())
+        .as_ref()
+        != Some(&decision)
+    {
+        println!("PLAN [{topic}] {decision}");
+    }
+}
+
+/// A store that reads from the real database but turns every write into a plan.
+pub struct DryRunStore<T: BridgeStore> {
+    inner: T,
+}
+
+impl<T: BridgeStore> DryRunStore<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl<T: BridgeStore> BridgeStore for DryRunStore<T> {
+    async fn all_bridges(&self) -> anyhow::Result<Vec<BridgeInfo>> {
+        self.inner.all_bridges().await
+    }
+
+    async fn group_bridges(&self, group: &str, status: &str) -> anyhow::Result<Vec<BridgeInfo>> {
+        self.inner.group_bridges(group, status).await
+    }
+
+    async fn stale_bridges(
+        &self,
+        group: &str,
+        status: &str,
+        older_than_secs: i64,
+    ) -> anyhow::Result<Vec<BridgeInfo>> {
+        self.inner
+            .stale_bridges(group, status, older_than_secs)
+            .await
+    }
+
+    async fn status_counts(&self, group: &str) -> anyhow::Result<BTreeMap<String, i64>> {
+        self.inner.status_counts(group).await
+    }
+
+    async fn pick_reserve(&self, group: &str) -> anyhow::Result<Option<BridgeInfo>> {
+        self.inner.pick_reserve(group).await
+    }
+
+    async fn insert_bridge(
+        &self,
+        bridge_id: &str,
+        ip_addr: &str,
+        group: &str,
+        status: &str,
+    ) -> anyhow::Result<()> {
+        plan(
+            &format!("bridge {bridge_id}"),
+            format!("would add {ip_addr} to {group} as {status}"),
+        );
+        Ok(())
+    }
+
+    async fn set_status(&self, bridge_id: &str, status: &str) -> anyhow::Result<()> {
+        plan(
+            &format!("bridge {bridge_id}"),
+            format!("would mark it {status}"),
+        );
+        Ok(())
+    }
+
+    async fn set_last_mbps(&self, ip_addr: &str, mbps: f64) -> anyhow::Result<()> {
+        log::debug!("DRY RUN: not recording {mbps} Mbps for {ip_addr}");
+        Ok(())
+    }
+
+    async fn delete_bridge(&self, bridge_id: &str) -> anyhow::Result<()> {
+        plan(&format!("bridge {bridge_id}"), "would delete it".into());
+        Ok(())
+    }
+
+    async fn delete_oldest(&self, group: &str, status: &str) -> anyhow::Result<()> {
+        let oldest = self
+            .inner
+            .group_bridges(group, status)
+            .await?
+            .into_iter()
+            .min_by_key(|b| b.change_time);
+        if let Some(oldest) = oldest {
+            plan(
+                &format!("bridge {}", oldest.bridge_id),
+                format!("would delete it as the oldest {status} bridge in {group}"),
+            );
+        }
+        Ok(())
+    }
+
+    async fn delete_with_status(&self, status: &str, older_than_secs: i64) -> anyhow::Result<u64> {
+        let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(older_than_secs);
+        let count = self
+            .inner
+            .all_bridges()
+            .await?
+            .iter()
+            .filter(|b| b.status == status && b.change_time <= cutoff)
+            .count();
+        plan(
+            &format!("prune {status}"),
+            format!("would delete {count} {status} bridges"),
+        );
+        Ok(0)
+    }
+
+    async fn delete_slowest(&self, group: &str) -> anyhow::Result<u64> {
+        let bridges: Vec<_> = self
+            .inner
+            .all_bridges()
+            .await?
+            .into_iter()
+            .filter(|b| b.alloc_group == group && b.last_mbps > 1.0)
+            .collect();
+        let slowest = bridges.iter().map(|b| b.last_mbps).reduce(f64::min);
+        for bridge in bridges.iter().filter(|b| Some(b.last_mbps) == slowest) {
+            plan(
+                &format!("bridge {}", bridge.bridge_id),
+                format!(
+                    "would delete it as the slowest bridge in {group} at {} Mbps",
+                    bridge.last_mbps
+                ),
+            );
+        }
+        Ok(0)
+    }
+
+    async fn set_group_delay(&self, group: &str, delay_ms: i32) -> anyhow::Result<()> {
+        plan(
+            &format!("delay {group}"),
+            format!("would delay clients by {delay_ms} ms"),
+        );
+        Ok(())
+    }
+
+    async fn claim_seen_ip(&self, ip_addr: &str) -> anyhow::Result<bool> {
+        log::debug!("DRY RUN: not recording {ip_addr} as seen");
+        Ok(true)
+    }
+
+    async fn record_blocked_ip(&self, ip_addr: &str) -> anyhow::Result<()> {
+        log::debug!("DRY RUN: not recording {ip_addr} as blocked");
+        Ok(())
+    }
+
+    async fn blocked_ips(&self, within_secs: i64) -> anyhow::Result<Vec<String>> {
+        self.inner.blocked_ips(within_secs).await
+    }
+
+    async fn host_key(&self, ip_addr: &str) -> anyhow::Result<Option<String>> {
+        self.inner.host_key(ip_addr).await
+    }
+
+    async fn set_host_key(&self, ip_addr: &str, _host_key: &str) -> anyhow::Result<()> {
+        log::debug!("DRY RUN: not pinning the host key of {ip_addr}");
+        Ok(())
+    }
+
+    async fn record_probe(
+        &self,
+        bridge_id: &str,
+        probe: &str,
+        verdict: &str,
+    ) -> anyhow::Result<()> {
+        log::debug!("DRY RUN: not recording that {probe} found {bridge_id} {verdict}");
+        Ok(())
+    }
+
+    async fn recent_probes(
+        &self,
+        bridge_id: &str,
+        window_secs: i64,
+    ) -> anyhow::Result<Vec<ProbeRecord>> {
+        self.inner.recent_probes(bridge_id, window_secs).await
+    }
+
+    async fn forget_probes(&self, _older_than_secs: i64) -> anyhow::Result<u64> {
+        Ok(0)
+    }
+
+    async fn record_block(
+        &self,
+        bridge_id: &str,
+        _ip_addr: &str,
+        evidence: &str,
+    ) -> anyhow::Result<()> {
+        plan(
+            &format!("bridge {bridge_id}"),
+            format!("would block it on the evidence {evidence}"),
+        );
+        Ok(())
+    }
+
+    async fn record_origin(
+        &self,
+        bridge_id: &str,
+        _group: &str,
+        provider: &str,
+    ) -> anyhow::Result<()> {
+        log::debug!("DRY RUN: not recording that {provider} created {bridge_id}");
+        Ok(())
+    }
+
+    async fn lifecycles(&self, group: &str) -> anyhow::Result<Vec<Lifecycle>> {
+        self.inner.lifecycles(group).await
+    }
+
+    async fn migrate(&self) -> anyhow::Result<()> {
+        log::info!("DRY RUN: not migrating the database");
+        Ok(())
+    }
+}
@@ -1,28 +1,68 @@
+pub mod aws;
+pub mod digitalocean;
+pub mod ec2;
+pub mod failover;
 pub mod hetzner;
 pub mod ip_fresher;
 pub mod lightsail;
 pub mod linode;
+#[cfg(test)]
+pub mod mock;
 pub mod oneprovider;
+pub mod openstack;
 pub mod ovh;
+pub mod reloading;
 pub mod scaleway;
 pub mod serverspace;
+pub mod throttle;
 pub mod vultr;
 
-use std::{process::Stdio, time::Duration};
+use std::{future::Future, process::Stdio, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 
+use crate::plan::{dry_run, plan};
+
 /// A specific service provider.
 #[async_trait]
 pub trait Provider: Send + Sync + 'static {
     /// Creates a new server
     async fn create_server(&self) -> anyhow::Result<CreatedServer>;
 
-    /// Retains only the servers that match the given predicate.
+    /// Retains only the servers that match the given predicate, among the servers this provider created. Servers without its tag are never touched.
     async fn retain_by_id(
         &self,
         pred: Box<dyn Fn(String) -> bool + Send + 'static>,
     ) -> anyhow::Result<()>;
+
+    /// Like [Provider::retain_by_id], but among the untagged servers that versions before owner tags created, recognized only by names that look like bridge IDs. Only safe on accounts that phalanx has to itself.
+    async fn retain_legacy_by_id(
+        &self,
+        _pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: Provider + ?Sized> Provider for Arc<T> {
+    async fn create_server(&self) -> anyhow::Result<CreatedServer> {
+        self.as_ref().create_server().await
+    }
+
+    async fn retain_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.as_ref().retain_by_id(pred).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.as_ref().retain_legacy_by_id(pred).await
+    }
 }
 
 pub struct CreatedServer {
@@ -51,6 +91,19 @@ async fn system(cmd: &str) -> anyhow::Result<String> {
     anyhow::Ok(std_output)
 }
 
+/// Deletes a server that a sweep found, or on a dry run only reports it.
+async fn sweep(what: &str, delete: impl Future<Output = anyhow::Result<()>>) -> anyhow::Result<()> {
+    if dry_run() {
+        plan(
+            &format!("sweep {what}"),
+            "would delete it as an orphan".into(),
+        );
+        return Ok(());
+    }
+    log::info!("deleting {what}");
+    delete.await
+}
+
 async fn wait_until_reachable(ip: &str) {
     log::debug!("waiting until {ip} is reachable...");
     while let Err(err) = system(&format!("nc -vzw 2 {ip} 22")).await {
@@ -0,0 +1,258 @@
+use std::{sync::LazyLock, time::Duration};
+
+use async_trait::async_trait;
+use dashmap::DashSet;
+use isahc::AsyncReadResponseExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{id::new_id, provider::CreatedServer};
+
+use super::{sweep, wait_until_reachable, Provider};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DigitalOceanConfig {
+    pub api_token: String,
+    pub region: String,
+    pub size: String,
+    pub image: String,
+    pub ssh_key_ids: Vec<u64>,
+}
+
+pub struct DigitalOceanProvider {
+    client: isahc::HttpClient,
+    cfg: DigitalOceanConfig,
+}
+
+impl DigitalOceanProvider {
+    /// Create a new DigitalOcean-based provider.
+    pub fn new(cfg: DigitalOceanConfig) -> Self {
+        let client = isahc::HttpClientBuilder::new()
+            .default_headers(&[
+                (
+                    "Authorization",
+                    format!("Bearer {}", cfg.api_token).as_str(),
+                ),
+                ("Content-Type", "application/json"),
+            ])
+            .build()
+            .unwrap();
+        Self { client, cfg }
+    }
+}
+
+/// Every droplet phalanx creates carries this tag, so that listing never sees anything else.
+const TAG: &str = "phalanx";
+
+#[derive(Clone, Debug, Serialize)]
+struct CreateDropletArgs {
+    name: String,
+    region: String,
+    size: String,
+    image: String,
+    ssh_keys: Vec<u64>,
+    tags: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Droplet {
+    id: u64,
+    name: String,
+    status: String,
+    region: DropletRegion,
+    networks: DropletNetworks,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DropletRegion {
+    slug: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DropletNetworks {
+    #[serde(default)]
+    v4: Vec<DropletAddress>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DropletAddress {
+    ip_address: String,
+    r#type: String,
+}
+
+impl Droplet {
+    fn public_ipv4(&self) -> Option<String> {
+        self.networks
+            .v4
+            .iter()
+            .find(|a| a.r#type == "public")
+            .map(|a| a.ip_address.clone())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DropletResponse {
+    droplet: Droplet,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DropletListResponse {
+    droplets: Vec<Droplet>,
+    #[serde(default)]
+    links: Links,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Links {
+    #[serde(default)]
+    pages: Pages,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Pages {
+    next: Option<String>,
+}
+
+fn doify_id(id: &str) -> String {
+    format!("phalanx-{id}")
+}
+
+/// Droplets that are still being created, which must survive a sweep even though they are not in the database yet.
+static CREATING: LazyLock<DashSet<u64>> = LazyLock::new(DashSet::new);
+
+#[async_trait]
+impl Provider for DigitalOceanProvider {
+    async fn create_server(&self) -> anyhow::Result<CreatedServer> {
+        let id = new_id();
+        let req = CreateDropletArgs {
+            name: doify_id(&id),
+            region: self.cfg.region.clone(),
+            size: self.cfg.size.clone(),
+            image: self.cfg.image.clone(),
+            ssh_keys: self.cfg.ssh_key_ids.clone(),
+            tags: vec![TAG.to_string()],
+        };
+
+        let mut resp = self
+            .client
+            .post_async(
+                "https://api.digitalocean.com/v2/droplets",
+                serde_json::to_vec(&req)?,
+            )
+            .await?;
+
+        if !resp.status().is_success() {
+            let r = resp.text().await?;
+            anyhow::bail!(
+                "non-success while creating droplet: {:?} {r}",
+                resp.status()
+            )
+        }
+
+        let droplet = resp.json::<DropletResponse>().await?.droplet;
+        CREATING.insert(droplet.id);
+        let droplet_id = droplet.id;
+        scopeguard::defer!({
+            CREATING.remove(&droplet_id);
+        });
+        // Wait for the droplet to boot and get a public address
+        loop {
+            let droplet = self.get_droplet(droplet_id).await?;
+
+            if let Some(ip_addr) = droplet.public_ipv4().filter(|_| droplet.status == "active") {
+                wait_until_reachable(&ip_addr).await;
+                return Ok(CreatedServer { id, ip_addr });
+            }
+
+            smol::Timer::after(Duration::from_secs(5)).await;
+        }
+    }
+
+    async fn retain_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        for droplet in self.list_all().await? {
+            let Some(id) = droplet.name.strip_prefix("phalanx-") else {
+                continue;
+            };
+
+            if !pred(id.to_string())
+                && !CREATING.contains(&droplet.id)
+                && droplet.region.slug == self.cfg.region
+            {
+                sweep(
+                    &format!("droplet {}", droplet.name),
+                    self.delete_droplet(droplet.id),
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl DigitalOceanProvider {
+    async fn list_all(&self) -> anyhow::Result<Vec<Droplet>> {
+        let mut droplets = vec![];
+        let mut next = Some(format!(
+            "https://api.digitalocean.com/v2/droplets?tag_name={TAG}&per_page=200"
+        ));
+        while let Some(url) = next {
+            let mut resp = self.client.get_async(&url).await?;
+
+            if !resp.status().is_success() {
+                let r = resp.text().await?;
+                anyhow::bail!(
+                    "non-success while listing droplets: {:?} {r}",
+                    resp.status()
+                )
+            }
+
+            let page: DropletListResponse = resp.json().await?;
+            droplets.extend(page.droplets);
+            next = page.links.pages.next;
+        }
+        Ok(droplets)
+    }
+
+    async fn get_droplet(&self, droplet_id: u64) -> anyhow::Result<Droplet> {
+        let mut resp = self
+            .client
+            .get_async(&format!(
+                "https://api.digitalocean.com/v2/droplets/{droplet_id}"
+            ))
+            .await?;
+
+        if !resp.status().is_success() {
+            let r = resp.text().await?;
+            anyhow::bail!(
+                "non-success while getting droplet details: {:?} {r}",
+                resp.status()
+            )
+        }
+
+        Ok(resp.json::<DropletResponse>().await?.droplet)
+    }
+
+    async fn delete_droplet(&self, droplet_id: u64) -> anyhow::Result<()> {
+        let mut resp = self
+            .client
+            .delete_async(&format!(
+                "https://api.digitalocean.com/v2/droplets/{droplet_id}"
+            ))
+            .await?;
+
+        log::debug!("DIGITALOCEAN DELETING {droplet_id}");
+
+        if !resp.status().is_success() {
+            let r = resp.text().await?;
+            anyhow::bail!(
+                "non-success while deleting droplet: {:?} {r}",
+                resp.status()
+            )
+        }
+
+        Ok(())
+    }
+}
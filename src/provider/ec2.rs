@@ -0,0 +1,345 @@
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use base64::Engine;
+use dashmap::DashSet;
+use serde::{Deserialize, Serialize};
+
+use crate::{id::new_id, provider::CreatedServer};
+
+use super::{
+    aws::{child_text, parse_xml, AwsClient},
+    sweep, wait_until_reachable, Provider,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ec2Config {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub region: String,
+
+    pub instance_type: String,
+    pub ami: String,
+    pub key_name: String,
+
+    /// Launch into this subnet rather than the default VPC.
+    #[serde(default)]
+    pub subnet_id: Option<String>,
+    #[serde(default)]
+    pub security_group_ids: Vec<String>,
+
+    /// Ask for spot instances, which are cheaper but may be taken back at any time.
+    #[serde(default)]
+    pub spot: bool,
+    /// The most to pay per hour for a spot instance, defaulting to the on-demand price.
+    #[serde(default)]
+    pub spot_max_price: Option<String>,
+}
+
+pub struct Ec2Provider {
+    client: AwsClient,
+    cfg: Ec2Config,
+}
+
+const API_VERSION: &str = "2016-11-15";
+
+/// Every instance phalanx creates carries this tag, holding its bridge ID.
+const OWNER_TAG: &str = "phalanx-id";
+
+/// How long a new instance gets to start running before it is given up on.
+const RUNNING_DEADLINE: Duration = Duration::from_secs(600);
+
+/// Lets root log in with the key pair, which stock images only allow for their default user.
+const USER_DATA: &str = "#cloud-config\ndisable_root: false\n";
+
+/// Instances that are still being created, which must survive a sweep even though they are not in the database yet.
+static CREATING: LazyLock<DashSet<String>> = LazyLock::new(DashSet::new);
+
+#[derive(Clone, Debug)]
+struct Instance {
+    instance_id: String,
+    bridge_id: Option<String>,
+    state: String,
+    ip_addr: Option<String>,
+}
+
+impl Ec2Provider {
+    /// Creates a new EC2 provider.
+    pub fn new(cfg: Ec2Config) -> Self {
+        let client = AwsClient::new(
+            &cfg.access_key_id,
+            &cfg.secret_access_key,
+            &cfg.region,
+            "ec2",
+            &format!("ec2.{}.amazonaws.com", cfg.region),
+        );
+        Self { client, cfg }
+    }
+
+    async fn call(&self, action: &str, params: Vec<(String, String)>) -> anyhow::Result<Vec<u8>> {
+        let mut form = vec![
+            ("Action".to_string(), action.to_string()),
+            ("Version".to_string(), API_VERSION.to_string()),
+        ];
+        form.extend(params);
+        self.client
+            .post(
+                "application/x-www-form-urlencoded; charset=utf-8",
+                &[],
+                serde_urlencoded::to_string(&form)?.into_bytes(),
+            )
+            .await
+            .with_context(|| format!("{action} failed"))
+    }
+
+    /// Lists the instances matching the given parameters, following every page.
+    async fn describe(&self, params: Vec<(String, String)>) -> anyhow::Result<Vec<Instance>> {
+        let mut instances = vec![];
+        let mut next_token: Option<String> = None;
+        loop {
+            let mut params = params.clone();
+            if let Some(token) = next_token.take() {
+                params.push(("NextToken".into(), token));
+            }
+            let resp = self.call("DescribeInstances", params).await?;
+            let doc = parse_xml(&resp)?;
+            instances.extend(parse_instances(&doc)?);
+            match child_text(doc.root_element(), "nextToken") {
+                Some(token) => next_token = Some(token),
+                None => return Ok(instances),
+            }
+        }
+    }
+
+    async fn terminate(&self, instance_id: &str) -> anyhow::Result<()> {
+        self.call(
+            "TerminateInstances",
+            vec![("InstanceId.1".into(), instance_id.into())],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Waits for the instance to run with a public address, returning that address.
+    async fn wait_running(&self, instance_id: &str) -> anyhow::Result<String> {
+        let deadline = Instant::now() + RUNNING_DEADLINE;
+        loop {
+            let instance = match self
+                .describe(vec![("InstanceId.1".into(), instance_id.into())])
+                .await
+            {
+                Ok(mut instances) => instances.pop(),
+                // a new instance may take a while to show up, since the API is only eventually consistent
+                Err(err)
+                    if err
+                        .root_cause()
+                        .to_string()
+                        .contains("InvalidInstanceID.NotFound") =>
+                {
+                    None
+                }
+                Err(err) if Instant::now() < deadline => {
+                    log::warn!(
+                        "<{}> cannot describe {instance_id} yet: {:?}",
+                        self.cfg.region,
+                        err
+                    );
+                    None
+                }
+                Err(err) => return Err(err),
+            };
+            match instance {
+                Some(Instance {
+                    state,
+                    ip_addr: Some(ip_addr),
+                    ..
+                }) if state == "running" => return Ok(ip_addr),
+                Some(Instance { state, .. })
+                    if state == "shutting-down" || state == "terminated" =>
+                {
+                    anyhow::bail!("EC2 instance {instance_id} was {state} before it came up")
+                }
+                _ if Instant::now() > deadline => {
+                    anyhow::bail!(
+                        "EC2 instance {instance_id} did not come up in {} secs",
+                        RUNNING_DEADLINE.as_secs()
+                    )
+                }
+                _ => {
+                    smol::Timer::after(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    }
+
+    fn run_params(&self, id: &str) -> Vec<(String, String)> {
+        let cfg = &self.cfg;
+        let mut params: Vec<(String, String)> = [
+            ("ImageId", cfg.ami.clone()),
+            ("InstanceType", cfg.instance_type.clone()),
+            ("MinCount", "1".into()),
+            ("MaxCount", "1".into()),
+            ("KeyName", cfg.key_name.clone()),
+            (
+                "UserData",
+                base64::engine::general_purpose::STANDARD.encode(USER_DATA),
+            ),
+            ("TagSpecification.1.ResourceType", "instance".into()),
+            ("TagSpecification.1.Tag.1.Key", OWNER_TAG.into()),
+            ("TagSpecification.1.Tag.1.Value", id.into()),
+            ("TagSpecification.1.Tag.2.Key", "Name".into()),
+            ("TagSpecification.1.Tag.2.Value", ec2ify_id(id)),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        // a subnet outside the default VPC only hands out public addresses when asked to
+        let group_prefix = if let Some(subnet_id) = &cfg.subnet_id {
+            params.extend([
+                ("NetworkInterface.1.DeviceIndex".into(), "0".into()),
+                ("NetworkInterface.1.SubnetId".into(), subnet_id.clone()),
+                (
+                    "NetworkInterface.1.AssociatePublicIpAddress".into(),
+                    "true".into(),
+                ),
+            ]);
+            "NetworkInterface.1.SecurityGroupId"
+        } else {
+            "SecurityGroupId"
+        };
+        for (i, group_id) in cfg.security_group_ids.iter().enumerate() {
+            params.push((format!("{group_prefix}.{}", i + 1), group_id.clone()));
+        }
+        if cfg.spot {
+            params.extend([
+                ("InstanceMarketOptions.MarketType".into(), "spot".into()),
+                (
+                    "InstanceMarketOptions.SpotOptions.SpotInstanceType".into(),
+                    "one-time".into(),
+                ),
+                (
+                    "InstanceMarketOptions.SpotOptions.InstanceInterruptionBehavior".into(),
+                    "terminate".into(),
+                ),
+            ]);
+            if let Some(max_price) = &cfg.spot_max_price {
+                params.push((
+                    "InstanceMarketOptions.SpotOptions.MaxPrice".into(),
+                    max_price.clone(),
+                ));
+            }
+        }
+        params
+    }
+}
+
+/// Pulls the instances out of a `RunInstances` or `DescribeInstances` response.
+fn parse_instances(doc: &roxmltree::Document) -> anyhow::Result<Vec<Instance>> {
+    let mut instances = vec![];
+    for set in doc.descendants().filter(|n| n.has_tag_name("instancesSet")) {
+        for item in set.children().filter(|c| c.has_tag_name("item")) {
+            let bridge_id = item
+                .children()
+                .filter(|c| c.has_tag_name("tagSet"))
+                .flat_map(|tags| tags.children().filter(|c| c.has_tag_name("item")))
+                .find(|tag| child_text(*tag, "key").as_deref() == Some(OWNER_TAG))
+                .and_then(|tag| child_text(tag, "value"));
+            let state = item
+                .children()
+                .find(|c| c.has_tag_name("instanceState"))
+                .and_then(|s| child_text(s, "name"))
+                .unwrap_or_default();
+            instances.push(Instance {
+                instance_id: child_text(item, "instanceId").context("instance without an ID")?,
+                bridge_id,
+                state,
+                ip_addr: child_text(item, "ipAddress"),
+            });
+        }
+    }
+    Ok(instances)
+}
+
+fn ec2ify_id(id: &str) -> String {
+    format!("ec2-phalanx-{id}")
+}
+
+#[async_trait]
+impl Provider for Ec2Provider {
+    async fn create_server(&self) -> anyhow::Result<CreatedServer> {
+        let id = new_id();
+        let resp = self.call("RunInstances", self.run_params(&id)).await?;
+        let instance_id = parse_instances(&parse_xml(&resp)?)?
+            .pop()
+            .context("RunInstances returned no instance")?
+            .instance_id;
+        CREATING.insert(instance_id.clone());
+        scopeguard::defer!({
+            CREATING.remove(&instance_id);
+        });
+        log::debug!(
+            "<{}> created EC2 instance {instance_id} for {id}",
+            self.cfg.region
+        );
+
+        let ip_addr = match self.wait_running(&instance_id).await {
+            Ok(ip_addr) => ip_addr,
+            Err(err) => {
+                // an instance that never came up is of no use, so do not leave it running until a sweep finds it
+                if let Err(err) = self.terminate(&instance_id).await {
+                    log::warn!(
+                        "<{}> FAILED TO terminate {instance_id} after it did not come up: {:?}",
+                        self.cfg.region,
+                        err
+                    );
+                }
+                return Err(err);
+            }
+        };
+        wait_until_reachable(&ip_addr).await;
+        Ok(CreatedServer { id, ip_addr })
+    }
+
+    async fn retain_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        let instances = self
+            .describe(vec![
+                ("Filter.1.Name".into(), "tag-key".into()),
+                ("Filter.1.Value.1".into(), OWNER_TAG.into()),
+                ("Filter.2.Name".into(), "instance-state-name".into()),
+                ("Filter.2.Value.1".into(), "pending".into()),
+                ("Filter.2.Value.2".into(), "running".into()),
+                ("Filter.2.Value.3".into(), "stopping".into()),
+                ("Filter.2.Value.4".into(), "stopped".into()),
+            ])
+            .await?;
+        for instance in instances {
+            let Some(bridge_id) = instance.bridge_id else {
+                continue;
+            };
+            if !pred(bridge_id.clone()) && !CREATING.contains(&instance.instance_id) {
+                if let Err(err) = sweep(
+                    &format!("EC2 instance {} of {bridge_id}", instance.instance_id),
+                    self.terminate(&instance.instance_id),
+                )
+                .await
+                {
+                    log::warn!(
+                        "<{}> FAILED TO terminate {} {:?}: {:?}",
+                        self.cfg.region,
+                        instance.instance_id,
+                        instance.ip_addr,
+                        err
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use crate::{config::group_config, database::DATABASE, loop_scoring::survival_score};
+
+use super::{
+    throttle::{health, Health},
+    CreatedServer, Provider,
+};
+
+/// One of the providers behind a [Failover].
+pub struct Member {
+    pub name: String,
+    pub weight: f64,
+    pub priority: u32,
+    pub provider: Arc<dyn Provider>,
+}
+
+/// Spreads server creation over several providers by weight, preferring healthy providers and lower priorities, and moves on to the next provider whenever one fails. Records which provider created each server, for the analytics.
+pub struct Failover {
+    group: String,
+    members: Vec<Member>,
+}
+
+impl Failover {
+    pub fn new(group: &str, members: Vec<Member>) -> Self {
+        Self {
+            group: group.to_string(),
+            members,
+        }
+    }
+
+    /// The weight of a member, scaled by how long its bridges survive if the group asks for that.
+    fn weight(&self, member: &Member) -> f64 {
+        if group_config(&self.group).is_some_and(|g| g.weight_by_survival) {
+            member.weight * survival_score(&self.group, &member.name)
+        } else {
+            member.weight
+        }
+    }
+
+    /// The members in the order to try them: best health and priority first, then shuffled by weight.
+    fn candidates(&self) -> Vec<&Member> {
+        let mut remaining: Vec<(Health, &Member)> = self
+            .members
+            .iter()
+            .map(|m| (health(&self.group, &m.name), m))
+            .collect();
+        let mut ordered = vec![];
+        while !remaining.is_empty() {
+            let best = remaining
+                .iter()
+                .map(|(health, m)| (*health, m.priority))
+                .min()
+                .unwrap();
+            let tier: Vec<usize> = (0..remaining.len())
+                .filter(|&i| (remaining[i].0, remaining[i].1.priority) == best)
+                .collect();
+            let total: f64 = tier.iter().map(|&i| self.weight(remaining[i].1)).sum();
+            let mut dart = fastrand::f64() * total;
+            let mut pick = tier[tier.len() - 1];
+            for &i in tier.iter() {
+                dart -= self.weight(remaining[i].1);
+                if dart < 0.0 {
+                    pick = i;
+                    break;
+                }
+            }
+            ordered.push(remaining.remove(pick).1);
+        }
+        ordered
+    }
+}
+
+#[async_trait]
+impl Provider for Failover {
+    async fn create_server(&self) -> Result<CreatedServer> {
+        let mut last_err = None;
+        for member in self.candidates() {
+            match member.provider.create_server().await {
+                Ok(created) => {
+                    log::debug!("{}: created {} on {}", self.group, created.id, member.name);
+                    if let Err(err) = DATABASE
+                        .record_origin(&created.id, &self.group, &member.name)
+                        .await
+                    {
+                        log::warn!(
+                            "{}: could not record where {} came from: {:?}",
+                            self.group,
+                            created.id,
+                            err
+                        );
+                    }
+                    return Ok(created);
+                }
+                Err(err) => {
+                    log::warn!(
+                        "{}: {} could not create a server: {:?}",
+                        self.group,
+                        member.name,
+                        err
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("the group has no providers")))
+    }
+
+    async fn retain_by_id(&self, pred: Box<dyn Fn(String) -> bool + Send + 'static>) -> Result<()> {
+        self.sweep_members(pred, false).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> Result<()> {
+        self.sweep_members(pred, true).await
+    }
+}
+
+impl Failover {
+    /// Sweeps every member, even if an earlier one fails.
+    async fn sweep_members(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+        legacy: bool,
+    ) -> Result<()> {
+        let pred = Arc::new(Mutex::new(pred));
+        let mut result = Ok(());
+        for member in self.members.iter() {
+            let pred = pred.clone();
+            let pred: Box<dyn Fn(String) -> bool + Send + 'static> =
+                Box::new(move |id| (pred.lock())(id));
+            let swept = if legacy {
+                member.provider.retain_legacy_by_id(pred).await
+            } else {
+                member.provider.retain_by_id(pred).await
+            };
+            if let Err(err) = swept {
+                log::warn!("{}: could not sweep {}: {:?}", self.group, member.name, err);
+                result = Err(err);
+            }
+        }
+        result
+    }
+}
@@ -1,14 +1,16 @@
+use std::collections::BTreeMap;
+
 use async_trait::async_trait;
 use isahc::{AsyncReadResponseExt, Request, RequestExt};
 use serde::{Deserialize, Serialize};
 use smol::io::AsyncReadExt;
 
 use crate::{
-    id::new_id,
+    id::{looks_like_id, new_id},
     provider::{wait_until_reachable, CreatedServer},
 };
 
-use super::Provider;
+use super::{sweep, Provider};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HetznerConfig {
@@ -29,6 +31,9 @@ impl HetznerProvider {
     }
 }
 
+/// Every server phalanx creates carries its bridge ID under this label.
+const OWNER_LABEL: &str = "phalanx-id";
+
 #[async_trait]
 impl Provider for HetznerProvider {
     async fn create_server(&self) -> anyhow::Result<CreatedServer> {
@@ -40,6 +45,7 @@ impl Provider for HetznerProvider {
             image: String,
             location: String,
             ssh_keys: Vec<String>,
+            labels: BTreeMap<String, String>,
         }
 
         let id = id.to_string();
@@ -53,6 +59,7 @@ impl Provider for HetznerProvider {
                 image: cfg.image.clone(),
                 location: cfg.location.clone(),
                 ssh_keys: vec![cfg.sshkey_id.clone()],
+                labels: [(OWNER_LABEL.to_string(), id.clone())].into(),
             })?)?
             .send_async()
             .await?;
@@ -75,61 +82,94 @@ impl Provider for HetznerProvider {
     async fn retain_by_id(
         &self,
         pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, false).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, true).await
+    }
+}
+
+impl HetznerProvider {
+    /// Deletes the servers whose bridge IDs fail the predicate, among the labelled servers, or on a legacy sweep among the unlabelled ones named after bridges.
+    async fn sweep_servers(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+        legacy: bool,
     ) -> anyhow::Result<()> {
         let cfg = self.cfg.clone();
-        // List all available servers
-        let resp = Request::get("https://api.hetzner.cloud/v1/servers")
+        // otherwise, only servers carrying our label are listed at all
+        let selector = if legacy {
+            String::new()
+        } else {
+            format!("label_selector={OWNER_LABEL}&")
+        };
+        for page in 1.. {
+            let resp = Request::get(format!(
+                "https://api.hetzner.cloud/v1/servers?{selector}per_page=50&page={page}"
+            ))
             .header("Authorization", format!("Bearer {}", cfg.api_token))
             .body("")?
             .send_async()
             .await?;
 
-        if !resp.status().is_success() {
-            return Err(anyhow::anyhow!("Failed to list servers: {}", resp.status()));
-        }
+            if !resp.status().is_success() {
+                return Err(anyhow::anyhow!("Failed to list servers: {}", resp.status()));
+            }
 
-        let mut body = Vec::new();
-        resp.into_body().read_to_end(&mut body).await?;
-        let json: serde_json::Value = serde_json::from_slice(&body)?;
-
-        // Filter the servers based on the given predicate
-        let servers = json["servers"]
-            .as_array()
-            .ok_or_else(|| anyhow::anyhow!("Failed to parse server list"))?;
-
-        for server in servers {
-            let server_id_str = server["name"]
-                .as_str()
-                .map(|s| s.to_string())
-                .unwrap_or_default();
-            log::debug!("looking at {server_id_str}");
-            if !(pred)(server_id_str.clone()) {
-                log::debug!("DELETING {server_id_str}");
-                // Delete the server if it doesn't match the predicate
-                let srv_id: i32 = server["id"]
-                    .as_i64()
-                    .ok_or_else(|| anyhow::anyhow!("Failed to get server id"))?
-                    as i32;
-
-                let mut delete_resp =
-                    Request::delete(format!("https://api.hetzner.cloud/v1/servers/{}", srv_id))
+            let mut body = Vec::new();
+            resp.into_body().read_to_end(&mut body).await?;
+            let json: serde_json::Value = serde_json::from_slice(&body)?;
+
+            let servers = json["servers"]
+                .as_array()
+                .ok_or_else(|| anyhow::anyhow!("Failed to parse server list"))?;
+
+            for server in servers {
+                let label = server["labels"][OWNER_LABEL].as_str();
+                let name = server["name"].as_str().unwrap_or_default();
+                let bridge_id = match (label, legacy) {
+                    (Some(id), false) => id,
+                    (None, true) if looks_like_id(name) => name,
+                    _ => continue,
+                };
+                log::debug!("looking at {bridge_id}");
+                if !(pred)(bridge_id.to_string()) {
+                    let srv_id = server["id"]
+                        .as_i64()
+                        .ok_or_else(|| anyhow::anyhow!("Failed to get server id"))?;
+                    sweep(&format!("hetzner server {bridge_id}"), async {
+                        let mut delete_resp = Request::delete(format!(
+                            "https://api.hetzner.cloud/v1/servers/{}",
+                            srv_id
+                        ))
                         .header("Authorization", format!("Bearer {}", cfg.api_token))
                         .body("")?
                         .send_async()
                         .await?;
 
-                if !delete_resp.status().is_success() {
-                    return Err(anyhow::anyhow!(
-                        "Failed to delete server: {} {}",
-                        delete_resp.status(),
-                        delete_resp.text().await?
-                    ));
+                        if !delete_resp.status().is_success() {
+                            return Err(anyhow::anyhow!(
+                                "Failed to delete server: {} {}",
+                                delete_resp.status(),
+                                delete_resp.text().await?
+                            ));
+                        }
+                        Ok(())
+                    })
+                    .await?;
                 }
             }
+
+            if json["meta"]["pagination"]["next_page"].is_null() {
+                break;
+            }
         }
 
         Ok(())
     }
-
-    // Implement the retain_by_id method here
 }
@@ -1,70 +1,104 @@
+use std::net::Ipv4Addr;
+
 use anyhow::Result;
 use async_trait::async_trait;
 
 use super::Provider;
-use crate::{database::DATABASE, provider::CreatedServer};
+use crate::{
+    config::IpFreshness, database::DATABASE, provider::CreatedServer,
+    ssh::forget_bootstrap_host_keys,
+};
 
+/// Keeps creating servers until one has an IP that was never used before, and that is not in a subnet where a bridge was recently blocked. Rejected servers are deleted right away, so that they stop costing money.
 pub struct IpFresher<T: Provider> {
     inner: T,
+    cfg: IpFreshness,
 }
 
 impl<T: Provider> IpFresher<T> {
-    pub fn new(provider: T) -> Self {
-        Self { inner: provider }
+    pub fn new(provider: T, cfg: IpFreshness) -> Self {
+        Self {
+            inner: provider,
+            cfg,
+        }
     }
 
-    // Helper function to check if an IP has been seen before
-    async fn is_ip_seen(&self, ip: &str) -> Result<bool> {
-        let result: bool =
-            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM phalanx_seen_ips WHERE ip_addr = $1)")
-                .bind(ip)
-                .fetch_one(&*DATABASE)
-                .await?;
-
-        Ok(result)
+    /// Why the IP is not fresh, if it is not. A fresh IP is recorded as seen, so that no other server gets accepted with it.
+    async fn staleness(&self, ip_addr: &str) -> Result<Option<String>> {
+        if let Some(prefix) = self.cfg.block_prefix {
+            let within_secs = self.cfg.block_memory_days as i64 * 86400;
+            for blocked in DATABASE.blocked_ips(within_secs).await? {
+                if same_subnet(ip_addr, &blocked, prefix) {
+                    return Ok(Some(format!("in the /{prefix} of the blocked {blocked}")));
+                }
+            }
+        }
+        // checked last, since claiming the IP is what accepts it
+        if !DATABASE.claim_seen_ip(ip_addr).await? {
+            return Ok(Some("already seen".into()));
+        }
+        Ok(None)
     }
+}
 
-    // Helper function to record a seen IP
-    async fn record_seen_ip(&self, ip: &str) -> Result<()> {
-        sqlx::query("INSERT INTO phalanx_seen_ips (ip_addr) VALUES ($1) ON CONFLICT DO NOTHING")
-            .bind(ip)
-            .execute(&*DATABASE)
-            .await?;
-
-        Ok(())
-    }
+/// Whether both are IPv4 addresses in the same subnet of the given prefix length.
+fn same_subnet(a: &str, b: &str, prefix: u8) -> bool {
+    let (Ok(a), Ok(b)) = (a.parse::<Ipv4Addr>(), b.parse::<Ipv4Addr>()) else {
+        return false;
+    };
+    let mask = u32::MAX
+        .checked_shl(32 - prefix.min(32) as u32)
+        .unwrap_or(0);
+    u32::from(a) & mask == u32::from(b) & mask
 }
 
 #[async_trait]
 impl<T: Provider> Provider for IpFresher<T> {
     async fn create_server(&self) -> Result<CreatedServer> {
-        for count in 0u64.. {
+        let max_attempts = self.cfg.max_attempts;
+        for attempt in 1..=max_attempts {
             let created = self.inner.create_server().await?;
-
-            // Check if we've seen this IP before
-            let seen = self.is_ip_seen(&created.ip_addr).await?;
-
-            if !seen {
-                // If this IP hasn't been seen before, add it to our database and return it
-                log::info!(
-                    "count={count}, IP {} SUCCESSFULLY GOT A FRESH FRESH server creation",
-                    created.ip_addr
-                );
-                self.record_seen_ip(&created.ip_addr).await?;
-                return Ok(created);
+            match self.staleness(&created.ip_addr).await? {
+                None => {
+                    log::info!(
+                        "attempt {attempt}/{max_attempts}: got the fresh IP {}",
+                        created.ip_addr
+                    );
+                    return Ok(created);
+                }
+                Some(reason) => {
+                    log::info!(
+                        "attempt {attempt}/{max_attempts}: IP {} is {reason}, retrying server creation",
+                        created.ip_addr
+                    );
+                    forget_bootstrap_host_keys(&created.ip_addr);
+                    let rejected = created.id.clone();
+                    if let Err(err) = self
+                        .inner
+                        .retain_by_id(Box::new(move |id| id != rejected))
+                        .await
+                    {
+                        log::warn!(
+                            "could not delete the rejected {}, leaving it to the next sweep: {:?}",
+                            created.id,
+                            err
+                        );
+                    }
+                }
             }
-
-            // If we've seen this IP before, try again by continuing the loop
-            log::info!(
-                "count={count}, IP {} already seen, retrying server creation",
-                created.ip_addr
-            );
         }
-        unreachable!()
+        anyhow::bail!("no fresh IP after {max_attempts} attempts")
     }
 
     async fn retain_by_id(&self, pred: Box<dyn Fn(String) -> bool + Send + 'static>) -> Result<()> {
         // Delegate to the inner provider
         self.inner.retain_by_id(pred).await
     }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> Result<()> {
+        self.inner.retain_legacy_by_id(pred).await
+    }
 }
@@ -1,15 +1,19 @@
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Context;
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
     id::new_id,
     provider::{system, wait_until_reachable, CreatedServer},
+    ssh::bootstrap_ssh_options,
 };
 
-use super::Provider;
+use super::{aws::AwsClient, sweep, Provider};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LightsailConfig {
@@ -22,63 +26,136 @@ pub struct LightsailConfig {
     pub bundle_id: String,
     pub key_pair_name: String,
 
+    /// Frontline bridges whose CPU stays above this percentage get replaced.
     pub target_cpu_usage: f64,
+    /// Frontline bridges whose burst capacity drops below this percentage get replaced.
+    #[serde(default = "default_min_burst_capacity")]
+    pub min_burst_capacity: f64,
+}
+
+fn default_min_burst_capacity() -> f64 {
+    10.0
 }
 
 pub struct LightsailProvider {
+    client: AwsClient,
     cfg: LightsailConfig,
 }
 
 impl LightsailProvider {
     /// Creates a new Lightsail provider.
     pub fn new(cfg: LightsailConfig) -> Self {
-        Self { cfg }
+        let client = AwsClient::new(
+            &cfg.access_key_id,
+            &cfg.secret_access_key,
+            &cfg.region,
+            "lightsail",
+            &format!("lightsail.{}.amazonaws.com", cfg.region),
+        );
+        Self { client, cfg }
+    }
+
+    /// Calls a Lightsail API action.
+    async fn call<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        action: &str,
+        req: &Req,
+    ) -> anyhow::Result<Resp> {
+        let resp = self
+            .client
+            .post(
+                "application/x-amz-json-1.1",
+                &[("x-amz-target", &format!("Lightsail_20161128.{action}"))],
+                serde_json::to_vec(req)?,
+            )
+            .await
+            .with_context(|| format!("{action} failed"))?;
+        serde_json::from_slice(&resp).with_context(|| format!("bad {action} response"))
+    }
+
+    async fn get_instance(&self, name: &str) -> anyhow::Result<Inner> {
+        let resp: SingleInstance = self
+            .call(
+                "GetInstance",
+                &InstanceNameArgs {
+                    instance_name: name,
+                },
+            )
+            .await?;
+        Ok(resp.instance)
+    }
+
+    async fn get_instances(&self) -> anyhow::Result<Vec<Inner>> {
+        let mut instances = vec![];
+        let mut page_token = None;
+        loop {
+            let resp: MultiInstances = self
+                .call("GetInstances", &GetInstancesArgs { page_token })
+                .await?;
+            instances.extend(resp.instances);
+            match resp.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(instances),
+            }
+        }
+    }
+
+    async fn delete_instance(&self, name: &str) -> anyhow::Result<()> {
+        let resp: Operations = self
+            .call(
+                "DeleteInstance",
+                &InstanceNameArgs {
+                    instance_name: name,
+                },
+            )
+            .await?;
+        resp.check("DeleteInstance")
+    }
+
+    /// The IDs of every bridge living on this account.
+    pub async fn bridge_ids(&self) -> anyhow::Result<HashSet<String>> {
+        Ok(self
+            .get_instances()
+            .await?
+            .into_iter()
+            .filter_map(|i| i.name.strip_prefix("aws-phalanx-").map(|s| s.to_string()))
+            .collect())
     }
 
     /// Query the burst capacity percentage of a particular server.
-    async fn burst_capacity_percent(&self, aws_name: &str) -> anyhow::Result<f64> {
-        let LightsailConfig {
-            access_key_id,
-            secret_access_key,
-            region,
-            availability_zone: _,
-            bundle_id: _,
-            key_pair_name: _,
-            target_cpu_usage: _,
-        } = &self.cfg;
-        let result = system(&format!("AWS_ACCESS_KEY_ID={access_key_id} AWS_SECRET_ACCESS_KEY={secret_access_key} AWS_DEFAULT_REGION={region} aws lightsail get-instance-metric-data --instance-name {aws_name} --metric-name BurstCapacityPercentage --unit Percent --start-time $(date -u -d '1 hour ago' +%FT%TZ) --end-time $(date -u +%FT%TZ) --period 600 --statistics Average")).await?;
-        let result: serde_json::Value = serde_json::from_str(&result)?;
-        let data = result["metricData"]
-            .as_array()
-            .context("metricData not an array")?
-            .iter()
-            .max_by_key(|v| v["timestamp"].as_f64().unwrap_or(0.0) as u64)
-            .context("metricData has no last")?["average"]
-            .as_f64()
-            .context("metricData last average no exist")?;
-        Ok(data)
+    pub async fn burst_capacity_percent(&self, aws_name: &str) -> anyhow::Result<f64> {
+        self.metric_average(aws_name, "BurstCapacityPercentage")
+            .await
     }
 
     /// Query the CPU usage percentage of a particular server.
-    async fn cpu_usage_percent(&self, aws_name: &str) -> anyhow::Result<f64> {
-        let LightsailConfig {
-            access_key_id,
-            secret_access_key,
-            region,
-            availability_zone: _,
-            bundle_id: _,
-            key_pair_name: _,
-            target_cpu_usage: _,
-        } = &self.cfg;
-        let result = system(&format!("AWS_ACCESS_KEY_ID={access_key_id} AWS_SECRET_ACCESS_KEY={secret_access_key} AWS_DEFAULT_REGION={region} aws lightsail get-instance-metric-data --instance-name {aws_name} --metric-name CPUUtilization --unit Percent --start-time $(date -u -d '1 hour ago' +%FT%TZ) --end-time $(date -u +%FT%TZ) --period 600 --statistics Average")).await?;
-        let result: serde_json::Value = serde_json::from_str(&result)?;
-        let data = result["metricData"]
-            .as_array()
-            .context("metricData not an array")?
+    pub async fn cpu_usage_percent(&self, aws_name: &str) -> anyhow::Result<f64> {
+        self.metric_average(aws_name, "CPUUtilization").await
+    }
+
+    /// The latest ten-minute average of a percentage metric over the last hour.
+    async fn metric_average(&self, aws_name: &str, metric_name: &str) -> anyhow::Result<f64> {
+        let end_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let resp: MetricData = self
+            .call(
+                "GetInstanceMetricData",
+                &GetInstanceMetricDataArgs {
+                    instance_name: aws_name,
+                    metric_name,
+                    period: 600,
+                    start_time: end_time - 3600,
+                    end_time,
+                    unit: "Percent",
+                    statistics: &["Average"],
+                },
+            )
+            .await?;
+        let data = resp
+            .metric_data
             .iter()
-            .max_by_key(|v| v["timestamp"].as_f64().unwrap_or(0.0) as u64)
-            .context("metricData has no last")?["average"]
-            .as_f64()
+            .max_by_key(|v| v.timestamp as u64)
+            .context("metricData has no last")?
+            .average
             .context("metricData last average no exist")?;
         Ok(data)
     }
@@ -89,26 +166,28 @@ impl Provider for LightsailProvider {
     async fn create_server(&self) -> anyhow::Result<CreatedServer> {
         let id = new_id();
         let name = id_to_name(&id);
-        let bundle_id = self.cfg.bundle_id.clone();
-        let availability_zone = self.cfg.availability_zone.clone();
-        let key_pair_name = self.cfg.key_pair_name.clone();
-        let access_key_id = self.cfg.access_key_id.clone();
-        let secret_access_key = self.cfg.secret_access_key.clone();
-        let region = self.cfg.region.clone();
-        system(&format!("AWS_ACCESS_KEY_ID={access_key_id} AWS_SECRET_ACCESS_KEY={secret_access_key} AWS_DEFAULT_REGION={region} aws lightsail create-instances --instance-names {name} --blueprint-id debian_11 --bundle-id {bundle_id} --availability-zone {availability_zone} --key-pair-name {key_pair_name}")).await?;
+        let availability_zone = &self.cfg.availability_zone;
+        let resp: Operations = self
+            .call(
+                "CreateInstances",
+                &CreateInstancesArgs {
+                    instance_names: vec![&name],
+                    availability_zone,
+                    blueprint_id: "debian_11",
+                    bundle_id: &self.cfg.bundle_id,
+                    key_pair_name: &self.cfg.key_pair_name,
+                },
+            )
+            .await?;
+        resp.check("CreateInstances")?;
         log::debug!("<{availability_zone}> created a lightsail instance {name} in");
         let ip_addr = loop {
-            let fallible_part = async {
-                let s = system(&format!("AWS_ACCESS_KEY_ID={access_key_id} AWS_SECRET_ACCESS_KEY={secret_access_key} AWS_DEFAULT_REGION={region} aws lightsail get-instance --instance-name {name}")).await?;
-                let j: SingleInstance = serde_json::from_str(&s)?;
-                if let Some(j) = j.instance.public_ip_address {
-                    anyhow::Ok(j)
-                } else {
-                    anyhow::bail!("no IP yet!")
-                }
-            };
-            match fallible_part.await {
-                Ok(res) => break res,
+            match self.get_instance(&name).await {
+                Ok(Inner {
+                    public_ip_address: Some(ip_addr),
+                    ..
+                }) => break ip_addr,
+                Ok(_) => log::debug!("no IP yet, waiting..."),
                 Err(err) => {
                     log::debug!("no IP ({:?}), waiting...", err);
                 }
@@ -117,14 +196,30 @@ impl Provider for LightsailProvider {
         };
         wait_until_reachable(&ip_addr).await;
         log::debug!("<{availability_zone}> instance {name} opening ports");
-        while let Err(err) = system(&format!(r#"AWS_ACCESS_KEY_ID={access_key_id} AWS_SECRET_ACCESS_KEY={secret_access_key} AWS_DEFAULT_REGION={region} aws lightsail open-instance-public-ports --instance-name {name} --port-info '{{"fromPort": 0, "toPort": 65535, "protocol": "all", "cidrs": ["0.0.0.0/0"]}}'"#)).await {
-                log::warn!("retrying... {:?}", err);
-                smol::Timer::after(Duration::from_secs(10)).await;
-            }
+        while let Err(err) = self
+            .call::<_, SingleOperation>(
+                "OpenInstancePublicPorts",
+                &OpenInstancePublicPortsArgs {
+                    instance_name: &name,
+                    port_info: PortInfo {
+                        from_port: 0,
+                        to_port: 65535,
+                        protocol: "all",
+                        cidrs: &["0.0.0.0/0"],
+                    },
+                },
+            )
+            .await
+            .and_then(|resp| resp.operation.check("OpenInstancePublicPorts"))
+        {
+            log::warn!("retrying... {:?}", err);
+            smol::Timer::after(Duration::from_secs(10)).await;
+        }
         log::debug!(
             "<{availability_zone}> instance {name} has ip {ip_addr}, enabling root access..."
         );
-        system(&format!("ssh -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null admin@{ip_addr} sudo cp ~admin/.ssh/authorized_keys ~root/.ssh/authorized_keys")).await?;
+        let opts = bootstrap_ssh_options(&ip_addr)?;
+        system(&format!("ssh {opts} admin@{ip_addr} sudo cp ~admin/.ssh/authorized_keys ~root/.ssh/authorized_keys")).await?;
         Ok(CreatedServer { ip_addr, id })
     }
 
@@ -132,58 +227,100 @@ impl Provider for LightsailProvider {
         &self,
         pred: Box<dyn Fn(String) -> bool + Send + 'static>,
     ) -> anyhow::Result<()> {
-        let availability_zone = self.cfg.availability_zone.clone(); // TODO take into account!!
-        let access_key_id = self.cfg.access_key_id.clone();
-        let secret_access_key = self.cfg.secret_access_key.clone();
-        let region = self.cfg.region.clone();
-        let s = system(&format!("AWS_ACCESS_KEY_ID={access_key_id} AWS_SECRET_ACCESS_KEY={secret_access_key} AWS_DEFAULT_REGION={region} aws lightsail get-instances")).await?;
+        let availability_zone = &self.cfg.availability_zone; // TODO take into account!!
         log::debug!("{} calling retain on aws", availability_zone);
-        let j: MultiInstances = serde_json::from_str(&s)?;
-        for instance in j.instances {
-            // if instance.state["name"] != "running" {
+        for instance in self.get_instances().await? {
+            // if instance.state.name != "running" {
             //     log::debug!(
             //         "skipping instance {} when delete due to not running",
             //         instance.name
             //     );
             //     continue;
             // }
-            if !pred(instance.name.replace("aws-phalanx-", ""))
-                && instance.name.contains("aws-phalanx-")
-            {
-                let availability_zone = availability_zone.clone();
-                let instance = instance.clone();
-                let access_key_id = access_key_id.clone();
-                let secret_access_key = secret_access_key.clone();
-                let region = region.clone();
-                let instance_name = instance.name;
-                if let Err(err) = system(&format!("AWS_ACCESS_KEY_ID={access_key_id} AWS_SECRET_ACCESS_KEY={secret_access_key} AWS_DEFAULT_REGION={region} aws lightsail delete-instance --instance-name {instance_name}")).await {
+            let Some(id) = instance.name.strip_prefix("aws-phalanx-") else {
+                continue;
+            };
+            if !pred(id.to_string()) {
+                if let Err(err) = sweep(
+                    &format!("lightsail instance {}", instance.name),
+                    self.delete_instance(&instance.name),
+                )
+                .await
+                {
                     log::warn!(
                         "<{availability_zone}> FAILED TO delete {} {:?}: {:?}",
-                        instance_name,
+                        instance.name,
                         instance.public_ip_address,
                         err
                     );
-                } else {
-                log::warn!(
-                    "<{availability_zone}> deleted {} {:?}",
-                    instance_name,
-                    instance.public_ip_address
-                );
-            }
+                }
             }
         }
         Ok(())
     }
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateInstancesArgs<'a> {
+    instance_names: Vec<&'a str>,
+    availability_zone: &'a str,
+    blueprint_id: &'a str,
+    bundle_id: &'a str,
+    key_pair_name: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InstanceNameArgs<'a> {
+    instance_name: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetInstancesArgs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OpenInstancePublicPortsArgs<'a> {
+    instance_name: &'a str,
+    port_info: PortInfo<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PortInfo<'a> {
+    from_port: u16,
+    to_port: u16,
+    protocol: &'a str,
+    cidrs: &'a [&'a str],
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetInstanceMetricDataArgs<'a> {
+    instance_name: &'a str,
+    metric_name: &'a str,
+    period: u64,
+    start_time: u64,
+    end_time: u64,
+    unit: &'a str,
+    statistics: &'a [&'a str],
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct SingleInstance {
     instance: Inner,
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct MultiInstances {
     instances: Vec<Inner>,
+    next_page_token: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -191,11 +328,67 @@ struct Inner {
     name: String,
     #[serde(rename = "publicIpAddress")]
     public_ip_address: Option<String>,
+}
+
+/// What the actions that change resources return, one operation per resource.
+#[derive(Debug, Clone, Deserialize)]
+struct Operations {
+    operations: Vec<Operation>,
+}
+
+impl Operations {
+    /// Fails if any of the operations did.
+    fn check(&self, action: &str) -> anyhow::Result<()> {
+        for operation in self.operations.iter() {
+            operation.check(action)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SingleOperation {
+    operation: Operation,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Operation {
+    resource_name: Option<String>,
+    /// `Started`, `Succeeded`, `Failed` and so on.
+    status: String,
+    error_code: Option<String>,
+    error_details: Option<String>,
+}
+
+impl Operation {
+    fn check(&self, action: &str) -> anyhow::Result<()> {
+        if self.status == "Failed" {
+            anyhow::bail!(
+                "{action} failed on {}: {} {}",
+                self.resource_name.as_deref().unwrap_or("?"),
+                self.error_code.as_deref().unwrap_or_default(),
+                self.error_details.as_deref().unwrap_or_default()
+            )
+        }
+        Ok(())
+    }
+}
 
-    state: serde_json::Value,
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MetricData {
+    metric_data: Vec<Datapoint>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Datapoint {
+    /// Seconds since the epoch.
+    timestamp: f64,
+    average: Option<f64>,
 }
 
 /// mangle a bridge ID to an AWS name
-fn id_to_name(id: &str) -> String {
+pub fn id_to_name(id: &str) -> String {
     format!("aws-phalanx-{}", id)
 }
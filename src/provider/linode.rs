@@ -5,9 +5,12 @@ use dashmap::DashSet;
 use isahc::AsyncReadResponseExt;
 use serde::{Deserialize, Serialize};
 
-use crate::{id::new_id, provider::CreatedServer};
+use crate::{
+    id::{looks_like_id, new_id},
+    provider::CreatedServer,
+};
 
-use super::{wait_until_reachable, Provider};
+use super::{sweep, wait_until_reachable, Provider};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LinodeConfig {
@@ -50,6 +53,7 @@ struct CreateLinodeArgs {
     root_pass: String,
     authorized_keys: Vec<String>,
     booted: bool,
+    tags: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -59,6 +63,8 @@ struct LinodeInstance {
     status: String,
     region: String,
     ipv4: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -66,8 +72,14 @@ struct LinodeListResponse {
     data: Vec<LinodeInstance>,
 }
 
+/// Every Linode phalanx creates carries this tag, so that other servers on the account are never swept.
+const TAG: &str = "phalanx";
+
+/// Linodes are labelled with their bridge ID after this prefix, which older versions also used, without the tag.
+const LABEL_PREFIX: &str = "bridge-";
+
 fn linodify_id(id: &str) -> String {
-    format!("bridge-{id}")
+    format!("{LABEL_PREFIX}{id}")
 }
 
 static CREATING: LazyLock<DashSet<i32>> = LazyLock::new(DashSet::new);
@@ -86,6 +98,7 @@ impl Provider for LinodeProvider {
             root_pass: cfg.root_pass.clone(),
             authorized_keys: cfg.authorized_keys.clone(),
             booted: true,
+            tags: vec![TAG.into()],
         };
 
         let mut resp = client
@@ -125,31 +138,56 @@ impl Provider for LinodeProvider {
     async fn retain_by_id(
         &self,
         pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, false).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, true).await
+    }
+}
+
+impl LinodeProvider {
+    /// Deletes the Linodes whose bridge IDs fail the predicate, among the ones carrying our tag, or on a legacy sweep among the untagged ones labelled after bridges.
+    async fn sweep_servers(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+        legacy: bool,
     ) -> anyhow::Result<()> {
         let instances = self.list_all().await?;
 
         for instance in instances {
-            let id = instance
-                .label
-                .strip_prefix("bridge-")
-                .unwrap_or(&instance.label)
-                .to_string();
+            let Some(id) = instance.label.strip_prefix(LABEL_PREFIX) else {
+                continue;
+            };
+            let tagged = instance.tags.iter().any(|t| t == TAG);
+            let ours = if legacy {
+                !tagged && looks_like_id(id)
+            } else {
+                tagged
+            };
+            if !ours {
+                continue;
+            }
 
-            if !pred(id.clone())
+            if !pred(id.to_string())
                 && !CREATING.contains(&instance.id)
                 && instance.region == self.cfg.region
             {
-                log::debug!("MUST DELETE {id}");
-
-                self.delete_server(&instance.id.to_string()).await?;
+                sweep(
+                    &format!("linode {}", instance.label),
+                    self.delete_server(&instance.id.to_string()),
+                )
+                .await?;
             }
         }
 
         Ok(())
     }
-}
 
-impl LinodeProvider {
     async fn list_all(&self) -> anyhow::Result<Vec<LinodeInstance>> {
         let mut resp = self
             .client
@@ -163,12 +201,7 @@ impl LinodeProvider {
 
         let response: LinodeListResponse = resp.json().await?;
 
-        // Filter instances to only include those with our naming pattern
-        Ok(response
-            .data
-            .into_iter()
-            .filter(|instance| instance.label.starts_with("bridge-"))
-            .collect())
+        Ok(response.data)
     }
 
     async fn get_server_by_id(&self, id: &str) -> anyhow::Result<LinodeInstance> {
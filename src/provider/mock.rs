@@ -0,0 +1,214 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use super::{CreatedServer, Provider};
+use crate::{id::new_id, ssh::CommandOutput};
+
+/// Knobs for the misbehavior a [MockProvider] should inject.
+#[derive(Clone, Debug, Default)]
+pub struct MockConfig {
+    /// How long every `create_server` call takes.
+    pub latency: Duration,
+    /// Probability that a `create_server` call fails outright.
+    pub failure_rate: f64,
+    /// Probability that a created server reuses an IP address handed out before.
+    pub duplicate_ip_rate: f64,
+    /// Probability that the services deployed on a created server never come up.
+    pub broken_rate: f64,
+}
+
+/// An in-memory provider that hands out fake servers, so that the control loops can be exercised without a cloud account.
+pub struct MockProvider {
+    cfg: Mutex<MockConfig>,
+    servers: Mutex<BTreeMap<String, String>>,
+    issued_ips: Mutex<Vec<String>>,
+    attempts: AtomicUsize,
+}
+
+/// A simulated machine behind a fake IP, answering the commands the loops send over SSH.
+#[derive(Clone, Debug, Default)]
+pub struct MockHost {
+    pub blocked: bool,
+    /// Drops pings, but nothing else.
+    pub icmp_filtered: bool,
+    /// Gets forged answers to its DNS queries.
+    pub dns_poisoned: bool,
+    pub mbps: f64,
+    pub host_key: String,
+    pub broken: bool,
+}
+
+impl MockHost {
+    fn fresh() -> Self {
+        Self {
+            host_key: format!("SHA256:{:016x}", fastrand::u64(..)),
+            ..Default::default()
+        }
+    }
+}
+
+/// All the simulated machines, keyed by IP address.
+static HOSTS: Lazy<DashMap<String, MockHost>> = Lazy::new(DashMap::new);
+
+/// Fake IPs come out of 198.18.0.0/15, which is reserved for benchmarking and never routed.
+static NEXT_IP: AtomicU32 = AtomicU32::new(1);
+
+impl MockProvider {
+    /// Creates a new mock provider.
+    pub fn new(cfg: MockConfig) -> Self {
+        Self {
+            cfg: Mutex::new(cfg),
+            servers: Default::default(),
+            issued_ips: Default::default(),
+            attempts: Default::default(),
+        }
+    }
+
+    /// Changes the injected misbehavior on the fly.
+    pub fn set_config(&self, cfg: MockConfig) {
+        *self.cfg.lock() = cfg;
+    }
+
+    /// How many times `create_server` was called, including the calls that failed.
+    pub fn attempts(&self) -> usize {
+        self.attempts.load(Ordering::SeqCst)
+    }
+
+    /// Returns the (id, ip) of every server that currently exists.
+    pub fn servers(&self) -> Vec<(String, String)> {
+        self.servers
+            .lock()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Pretends that a server was created behind our back, e.g. by a crashed earlier run.
+    pub fn add_orphan(&self) -> String {
+        let id = new_id();
+        let ip_addr = fresh_ip();
+        HOSTS.insert(ip_addr.clone(), MockHost::fresh());
+        self.servers.lock().insert(id.clone(), ip_addr);
+        id
+    }
+}
+
+/// Changes how the simulated machine at the given IP behaves.
+pub fn update_host(ip_addr: &str, f: impl FnOnce(&mut MockHost)) {
+    if let Some(mut host) = HOSTS.get_mut(ip_addr) {
+        f(&mut host)
+    }
+}
+
+/// Returns the host key of a simulated machine, or None if the host is not a simulated one.
+pub fn host_key(host: &str) -> Option<String> {
+    HOSTS.get(host).map(|h| h.host_key.clone())
+}
+
+/// Answers an SSH command on behalf of a simulated machine, along with the host key it presented. Returns None if the host is not a simulated one.
+pub fn intercept_ssh(ip_addr: &str, cmd: &str) -> Option<(String, CommandOutput)> {
+    let host = HOSTS.get(ip_addr)?;
+    if host.broken && cmd.contains("systemctl cat") {
+        return Some((
+            host.host_key.clone(),
+            CommandOutput {
+                exit_status: 1,
+                stdout: String::new(),
+                stderr: "unit is not installed".into(),
+            },
+        ));
+    }
+    let stdout = if cmd.contains("/dev/tcp") {
+        if host.blocked {
+            "closed\nclosed\n"
+        } else {
+            "open\nclosed\n"
+        }
+        .into()
+    } else if cmd.contains("dig") {
+        if host.blocked {
+            String::new()
+        } else if host.dns_poisoned {
+            "198.51.100.1\n".into()
+        } else {
+            format!("{ip_addr}\n")
+        }
+    } else if cmd.contains("ping") {
+        if host.blocked || host.icmp_filtered {
+            "10 packets transmitted, 0 received, 100% packet loss".into()
+        } else {
+            "10 packets transmitted, 10 received, 0% packet loss".into()
+        }
+    } else if cmd.contains("rx_bytes") {
+        format!("{:.2}\n", host.mbps)
+    } else {
+        String::new()
+    };
+    Some((
+        host.host_key.clone(),
+        CommandOutput {
+            exit_status: 0,
+            stdout,
+            stderr: String::new(),
+        },
+    ))
+}
+
+fn fresh_ip() -> String {
+    let n = NEXT_IP.fetch_add(1, Ordering::SeqCst);
+    format!(
+        "198.{}.{}.{}",
+        18 + (n >> 16) % 2,
+        (n >> 8) & 0xff,
+        n & 0xff
+    )
+}
+
+#[async_trait]
+impl Provider for MockProvider {
+    async fn create_server(&self) -> Result<CreatedServer> {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+        let cfg = self.cfg.lock().clone();
+        smol::Timer::after(cfg.latency).await;
+        if fastrand::f64() < cfg.failure_rate {
+            anyhow::bail!("mock provider injected a failure")
+        }
+        let ip_addr = {
+            let mut issued = self.issued_ips.lock();
+            if !issued.is_empty() && fastrand::f64() < cfg.duplicate_ip_rate {
+                issued[fastrand::usize(..issued.len())].clone()
+            } else {
+                let ip = fresh_ip();
+                issued.push(ip.clone());
+                ip
+            }
+        };
+        HOSTS.entry(ip_addr.clone()).or_insert_with(|| MockHost {
+            broken: fastrand::f64() < cfg.broken_rate,
+            ..MockHost::fresh()
+        });
+        let id = new_id();
+        self.servers.lock().insert(id.clone(), ip_addr.clone());
+        Ok(CreatedServer { id, ip_addr })
+    }
+
+    async fn retain_by_id(&self, pred: Box<dyn Fn(String) -> bool + Send + 'static>) -> Result<()> {
+        self.servers.lock().retain(|id, ip_addr| {
+            let keep = pred(id.clone());
+            if !keep {
+                log::debug!("MOCK DELETING {id} ({ip_addr})");
+            }
+            keep
+        });
+        Ok(())
+    }
+}
@@ -7,11 +7,12 @@ use serde_json::Value;
 use smol::io::AsyncReadExt;
 
 use crate::{
-    id::new_id,
+    id::{looks_like_id, new_id},
     provider::{system, CreatedServer},
+    ssh::bootstrap_ssh_options,
 };
 
-use super::{wait_until_reachable, Provider};
+use super::{sweep, wait_until_reachable, Provider};
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct OneCloudConfig {
     pub api_key: String,
@@ -32,12 +33,15 @@ impl OneCloudProvider {
     }
 }
 
+/// Servers have nowhere to put a tag, so every hostname phalanx picks starts with this.
+const HOSTNAME_PREFIX: &str = "phalanx-";
+
 #[async_trait]
 impl Provider for OneCloudProvider {
     async fn create_server(&self) -> anyhow::Result<CreatedServer> {
         let phalanx_id = new_id();
         let create_server_req = vec![
-            ("hostname", phalanx_id.to_string()),
+            ("hostname", format!("{HOSTNAME_PREFIX}{phalanx_id}")),
             ("location_id", self.cfg.location_id.to_string()),
             ("instance_size", self.cfg.instance_size.clone()),
             ("template", self.cfg.template.to_string()),
@@ -76,7 +80,11 @@ impl Provider for OneCloudProvider {
         let password = resp["response"]["password"]
             .as_str()
             .context("no password")?;
-        system(&format!("sshpass -p {password} ssh-copy-id -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null root@{ip_addr}")).await?;
+        let opts = bootstrap_ssh_options(&ip_addr)?;
+        system(&format!(
+            "sshpass -p {password} ssh-copy-id {opts} root@{ip_addr}"
+        ))
+        .await?;
         // ssh_execute(&ip_addr, "apt update -y").await?;
         Ok(CreatedServer {
             ip_addr,
@@ -87,6 +95,24 @@ impl Provider for OneCloudProvider {
     async fn retain_by_id(
         &self,
         pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, false).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, true).await
+    }
+}
+
+impl OneCloudProvider {
+    /// Deletes the servers whose bridge IDs fail the predicate, among the servers with our hostname prefix, or on a legacy sweep among the ones named after bare bridge IDs.
+    async fn sweep_servers(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+        legacy: bool,
     ) -> anyhow::Result<()> {
         let cfg = self.cfg.clone();
         let url = "https://api.oneprovider.com/vm/list";
@@ -112,10 +138,18 @@ impl Provider for OneCloudProvider {
             let server_name = server["domain"]
                 .as_str()
                 .ok_or(anyhow::Error::msg("No server name found"))?;
-
-            if !pred(server_name.to_string()) {
-                log::debug!("ONEPROVIDER DELETING {}", server_name);
-                delete_server(&cfg, server_id).await?;
+            let bridge_id = match (server_name.strip_prefix(HOSTNAME_PREFIX), legacy) {
+                (Some(id), false) => id,
+                (None, true) if looks_like_id(server_name) => server_name,
+                _ => continue,
+            };
+
+            if !pred(bridge_id.to_string()) {
+                sweep(
+                    &format!("oneprovider server {server_name}"),
+                    delete_server(&cfg, server_id),
+                )
+                .await?;
             }
         }
 
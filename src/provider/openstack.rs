@@ -0,0 +1,246 @@
+use std::sync::LazyLock;
+
+use anyhow::Context;
+use async_compat::CompatExt;
+use async_trait::async_trait;
+use dashmap::DashSet;
+use futures_util::TryStreamExt;
+use openstack::{auth::Password, waiter::Waiter, IdOrName};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    id::{looks_like_id, new_id},
+    provider::{system, wait_until_reachable, CreatedServer},
+    ssh::bootstrap_ssh_options,
+};
+
+use super::{sweep, Provider};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OpenStackConfig {
+    /// The Keystone v3 endpoint, such as `https://auth.cloud.ovh.net/v3`.
+    pub auth_url: String,
+    pub username: String,
+    pub password: String,
+    #[serde(default = "default_domain")]
+    pub user_domain: String,
+    pub project: String,
+    #[serde(default = "default_domain")]
+    pub project_domain: String,
+    pub region: String,
+
+    pub flavor: String,
+    pub image: String,
+    pub network: String,
+    pub keypair_name: String,
+
+    /// The user the image lets in with the key pair, whose keys get copied to root.
+    #[serde(default = "default_login_user")]
+    pub login_user: String,
+    /// Give every server a floating IP from this external network, for clouds that only hand out private addresses.
+    #[serde(default)]
+    pub floating_network: Option<String>,
+}
+
+fn default_domain() -> String {
+    "Default".into()
+}
+
+fn default_login_user() -> String {
+    "debian".into()
+}
+
+pub struct OpenStackProvider {
+    cfg: OpenStackConfig,
+}
+
+/// Every server and floating IP phalanx creates carries its bridge ID under this key.
+const OWNER_KEY: &str = "phalanx-id";
+
+/// Bridges that are still being created, which must survive a sweep even though they are not in the database yet.
+static CREATING: LazyLock<DashSet<String>> = LazyLock::new(DashSet::new);
+
+impl OpenStackProvider {
+    /// Creates a new OpenStack provider.
+    pub fn new(cfg: OpenStackConfig) -> Self {
+        Self { cfg }
+    }
+
+    /// Logs in with the configured credentials, without going through the environment.
+    async fn cloud(&self) -> anyhow::Result<openstack::Cloud> {
+        let cfg = &self.cfg;
+        let auth = Password::new(
+            &cfg.auth_url,
+            cfg.username.clone(),
+            cfg.password.clone(),
+            cfg.user_domain.clone(),
+        )?
+        .with_project_scope(
+            IdOrName::Name(cfg.project.clone()),
+            IdOrName::Name(cfg.project_domain.clone()),
+        );
+        let mut cloud = openstack::Cloud::new(auth)
+            .compat()
+            .await
+            .context("could not log in to OpenStack")?;
+        cloud.endpoint_filters_mut().set_region(cfg.region.clone());
+        Ok(cloud)
+    }
+}
+
+#[async_trait]
+impl Provider for OpenStackProvider {
+    /// Creates a new server, returning an IP address reachable through SSH port 22 and "root".
+    async fn create_server(&self) -> anyhow::Result<CreatedServer> {
+        let id = new_id();
+        CREATING.insert(id.clone());
+        scopeguard::defer!({
+            CREATING.remove(&id);
+        });
+        let os = self.cloud().await?;
+        let cfg = &self.cfg;
+
+        log::info!("<{}> creating OpenStack server {id}...", cfg.region);
+        let server = async {
+            os.new_server(id.clone(), cfg.flavor.clone())
+                .with_image(cfg.image.clone())
+                .with_network(cfg.network.clone())
+                .with_keypair(cfg.keypair_name.clone())
+                .with_metadata(OWNER_KEY, id.clone())
+                .create()
+                .await
+                .context("could not create OpenStack server")?
+                .wait()
+                .await
+                .context("server did not reach ACTIVE state")
+        }
+        .compat()
+        .await?;
+
+        let ipv4 = match &cfg.floating_network {
+            Some(floating_network) => {
+                let port = os
+                    .find_ports()
+                    .with_device_id(server.id().clone())
+                    .one()
+                    .compat()
+                    .await
+                    .context("server has no port for a floating IP")?;
+                os.new_floating_ip(floating_network.clone())
+                    .with_port(port)
+                    .with_description(format!("{OWNER_KEY}={id}"))
+                    .create()
+                    .compat()
+                    .await
+                    .context("could not attach a floating IP")?
+                    .floating_ip_address()
+            }
+            None => server
+                .addresses()
+                .values()
+                .flat_map(|val| val.iter())
+                .map(|addr| addr.addr)
+                .find(|addr| addr.is_ipv4())
+                .context("no ipv4 address?!?!?!")?,
+        };
+        wait_until_reachable(&ipv4.to_string()).await;
+        if cfg.login_user != "root" {
+            let user = &cfg.login_user;
+            let opts = bootstrap_ssh_options(&ipv4.to_string())?;
+            system(&format!("ssh {opts} {user}@{ipv4} sudo cp ~{user}/.ssh/authorized_keys ~root/.ssh/authorized_keys")).await?;
+            log::debug!("ENABLED ROOT ACCESS FOR OPENSTACK {ipv4}");
+        }
+
+        Ok(CreatedServer {
+            id: id.clone(),
+            ip_addr: ipv4.to_string(),
+        })
+    }
+
+    /// Retains only the servers that match the given predicate.
+    async fn retain_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, false).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, true).await
+    }
+}
+
+impl OpenStackProvider {
+    /// Deletes the servers and floating IPs whose bridge IDs fail the predicate, among those carrying our owner tag, or on a legacy sweep among the untagged servers named after bridges.
+    async fn sweep_servers(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+        legacy: bool,
+    ) -> anyhow::Result<()> {
+        let os = self.cloud().await?;
+        let region = &self.cfg.region;
+        let servers: Vec<_> = os
+            .find_servers()
+            .detailed()
+            .into_stream()
+            .try_collect()
+            .compat()
+            .await?;
+        // floating IPs outlive their servers, so they need sweeping too
+        let floating_ips = os.list_floating_ips().compat().await?;
+
+        let (servers, floating_ips) = {
+            let doomed = |id: &str| !pred(id.to_string()) && !CREATING.contains(id);
+            let servers: Vec<_> = servers
+                .into_iter()
+                .filter_map(|server| {
+                    let id = match (server.metadata().get(OWNER_KEY), legacy) {
+                        (Some(id), false) => id.clone(),
+                        (None, true) if looks_like_id(server.name()) => server.name().clone(),
+                        _ => return None,
+                    };
+                    doomed(&id).then_some((id, server))
+                })
+                .collect();
+            let floating_ips: Vec<_> = floating_ips
+                .into_iter()
+                // older versions never created floating IPs
+                .filter(|_| !legacy)
+                .filter_map(|floating_ip| {
+                    let id = floating_ip
+                        .description()
+                        .as_deref()?
+                        .strip_prefix(OWNER_KEY)?
+                        .strip_prefix('=')?
+                        .to_string();
+                    doomed(&id).then_some((id, floating_ip))
+                })
+                .collect();
+            (servers, floating_ips)
+        };
+
+        for (id, server) in servers {
+            sweep(&format!("<{region}> server {id}"), async {
+                server.delete().compat().await?;
+                Ok(())
+            })
+            .await?;
+        }
+        for (id, floating_ip) in floating_ips {
+            let what = format!(
+                "<{region}> floating IP {} of {id}",
+                floating_ip.floating_ip_address()
+            );
+            sweep(&what, async {
+                floating_ip.delete().compat().await?;
+                Ok(())
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+}
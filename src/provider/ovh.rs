@@ -1,20 +1,18 @@
 use std::collections::BTreeMap;
 
-use crate::{
-    id::new_id,
-    provider::{system, wait_until_reachable, CreatedServer},
-};
+use crate::provider::CreatedServer;
 
-use super::Provider;
-use anyhow::Context;
-use async_compat::CompatExt;
+use super::{
+    openstack::{OpenStackConfig, OpenStackProvider},
+    Provider,
+};
 use async_trait::async_trait;
-use openstack::waiter::Waiter;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OvhConfig {
-    env_variables: BTreeMap<String, String>,
+    /// The `OS_*` variables of an OVH `openrc.sh`.
+    pub env_variables: BTreeMap<String, String>,
 
     flavor: String,
     network: String,
@@ -22,110 +20,63 @@ pub struct OvhConfig {
     keypair_name: String,
 }
 
+impl OvhConfig {
+    /// The equivalent plain OpenStack configuration.
+    fn to_openstack(&self) -> OpenStackConfig {
+        let var = |keys: &[&str], default: &str| {
+            keys.iter()
+                .find_map(|k| self.env_variables.get(*k))
+                .cloned()
+                .unwrap_or_else(|| default.to_string())
+        };
+        OpenStackConfig {
+            auth_url: var(&["OS_AUTH_URL"], "https://auth.cloud.ovh.net/v3"),
+            username: var(&["OS_USERNAME"], ""),
+            password: var(&["OS_PASSWORD"], ""),
+            user_domain: var(&["OS_USER_DOMAIN_NAME"], "Default"),
+            project: var(&["OS_PROJECT_NAME", "OS_TENANT_NAME"], ""),
+            project_domain: var(&["OS_PROJECT_DOMAIN_NAME"], "Default"),
+            region: var(&["OS_REGION_NAME"], ""),
+            flavor: self.flavor.clone(),
+            image: self.image.clone(),
+            network: self.network.clone(),
+            keypair_name: self.keypair_name.clone(),
+            login_user: "debian".into(),
+            floating_network: None,
+        }
+    }
+}
+
+/// OVH Public Cloud, which is plain OpenStack configured through `openrc.sh` variables.
 pub struct OvhProvider {
-    cfg: OvhConfig,
+    inner: OpenStackProvider,
 }
 
 impl OvhProvider {
     pub fn new(cfg: OvhConfig) -> Self {
-        Self { cfg }
+        Self {
+            inner: OpenStackProvider::new(cfg.to_openstack()),
+        }
     }
 }
 
 #[async_trait]
 impl Provider for OvhProvider {
-    /// Creates a new server, returning an IP address reachable through SSH port 22 and "root".
     async fn create_server(&self) -> anyhow::Result<CreatedServer> {
-        // Horrifying hax: set the env variables here lol
-        for (k, v) in self.cfg.env_variables.iter() {
-            std::env::set_var(k, v);
-        }
-        let name = new_id();
-
-        let os = openstack::Cloud::from_env()
-            .compat()
-            .await
-            .context("Failed to create a Cloud object from the environment")?;
-
-        log::info!("Creating OVH server...");
-        let config = self.cfg.clone();
-        let waiter = os
-            .new_server(name.clone(), config.flavor)
-            .with_image(config.image)
-            .with_network(config.network)
-            .with_keypair(config.keypair_name)
-            .create()
-            .await
-            .context("Failed to create OVH server")?;
-        {
-            let current = waiter.current_state();
-            log::info!(
-                "ID = {}, Name = {}, Status = {:?}, Power = {:?}, Flavor = {:?}",
-                current.id(),
-                current.name(),
-                current.status(),
-                current.power_state(),
-                current.flavor(),
-            );
-        }
-
-        let server = waiter
-            .wait()
-            .await
-            .context("Server did not reach ACTIVE state")?;
-        log::info!(
-            "Successfully created server -- ID = {}, Name = {}, Status = {:?}, Power = {:?}",
-            server.id(),
-            server.name(),
-            server.status(),
-            server.power_state()
-        );
-        let ipv4 = server
-            .addresses()
-            .values()
-            .flat_map(|val| val.iter())
-            .find_map(|addr| {
-                if addr.addr.is_ipv4() {
-                    Some(addr.addr)
-                } else {
-                    None
-                }
-            })
-            .context("no ipv4 address?!?!?!")?;
-        wait_until_reachable(&ipv4.to_string()).await;
-        // enable root login
-        system(&dbg!(format!("ssh -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null debian@{ipv4} sudo cp /home/debian/.ssh/authorized_keys /root/.ssh/authorized_keys"))).await?;
-        log::debug!("ENABLED ROOT ACCESS FOR OVH {ipv4}");
-
-        Ok(CreatedServer {
-            id: name,
-            ip_addr: ipv4.to_string(),
-        })
+        self.inner.create_server().await
     }
 
-    /// Retains only the servers that match the given predicate.
     async fn retain_by_id(
         &self,
         pred: Box<dyn Fn(String) -> bool + Send + 'static>,
     ) -> anyhow::Result<()> {
-        for (k, v) in self.cfg.env_variables.iter() {
-            std::env::set_var(k, v);
-        }
-
-        let os = openstack::Cloud::from_env()
-            .await
-            .context("Failed to create a Cloud object from the environment")?;
-
-        let servers = os.list_servers().await?;
-        for server in servers.iter() {
-            let name = server.name();
-            if !pred(name.clone()) {
-                log::info!("{:?} about to deleting server: {:?}", self.cfg, name);
-                server.clone().delete().await?;
-                log::info!("successfully deleted server: {:?}", name);
-            }
-        }
+        self.inner.retain_by_id(pred).await
+    }
 
-        Ok(())
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.inner.retain_legacy_by_id(pred).await
     }
 }
@@ -0,0 +1,118 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use crate::config::{group_config, GroupConfig};
+
+use super::{throttle::forget_health, CreatedServer, Provider};
+
+/// Rebuilds the providers of a group whenever a reload changes what they are built from. Sweeps go through every provider it ever built, since the servers of a replaced one would otherwise never be deleted.
+pub struct Reloading {
+    group: String,
+    build: BuildFn,
+    built: Mutex<Built>,
+}
+
+type BuildFn = Box<dyn Fn(&GroupConfig) -> Arc<dyn Provider> + Send + Sync>;
+
+struct Built {
+    /// The key of the provider in use.
+    current: String,
+    /// Every provider built so far, by key.
+    providers: BTreeMap<String, Arc<dyn Provider>>,
+}
+
+/// What the providers of a group are built from.
+fn build_key(cfg: &GroupConfig) -> String {
+    serde_yaml::to_string(&(
+        cfg.provider_choices(),
+        &cfg.create_limit,
+        cfg.fresh_ips,
+        &cfg.ip_freshness,
+    ))
+    .unwrap_or_default()
+}
+
+impl Reloading {
+    pub fn new(
+        group: &str,
+        cfg: &GroupConfig,
+        build: impl Fn(&GroupConfig) -> Arc<dyn Provider> + Send + Sync + 'static,
+    ) -> Self {
+        let key = build_key(cfg);
+        let provider = build(cfg);
+        Self {
+            group: group.to_string(),
+            build: Box::new(build),
+            built: Mutex::new(Built {
+                current: key.clone(),
+                providers: BTreeMap::from([(key, provider)]),
+            }),
+        }
+    }
+
+    /// The provider for the current config of the group, or for the last one if the group was removed.
+    fn current(&self) -> Arc<dyn Provider> {
+        let mut built = self.built.lock();
+        let Built { current, providers } = &mut *built;
+        if let Some(cfg) = group_config(&self.group) {
+            let key = build_key(&cfg);
+            if &key != current {
+                log::info!("{}: the providers changed, switching to them", self.group);
+                forget_health(&self.group);
+                providers
+                    .entry(key.clone())
+                    .or_insert_with(|| (self.build)(&cfg));
+                *current = key;
+            }
+        }
+        providers[current].clone()
+    }
+
+    /// Sweeps with every provider, even if an earlier one fails.
+    async fn sweep_all(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+        legacy: bool,
+    ) -> Result<()> {
+        self.current();
+        let providers: Vec<_> = self.built.lock().providers.values().cloned().collect();
+        let pred = Arc::new(Mutex::new(pred));
+        let mut result = Ok(());
+        for provider in providers {
+            let pred = pred.clone();
+            let pred: Box<dyn Fn(String) -> bool + Send + 'static> =
+                Box::new(move |id| (pred.lock())(id));
+            let swept = if legacy {
+                provider.retain_legacy_by_id(pred).await
+            } else {
+                provider.retain_by_id(pred).await
+            };
+            if let Err(err) = swept {
+                log::warn!("{}: could not sweep: {:?}", self.group, err);
+                result = Err(err);
+            }
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Provider for Reloading {
+    async fn create_server(&self) -> Result<CreatedServer> {
+        self.current().create_server().await
+    }
+
+    async fn retain_by_id(&self, pred: Box<dyn Fn(String) -> bool + Send + 'static>) -> Result<()> {
+        self.sweep_all(pred, false).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> Result<()> {
+        self.sweep_all(pred, true).await
+    }
+}
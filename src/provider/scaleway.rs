@@ -14,11 +14,11 @@ use serde_json::{json, Value};
 use smol::io::AsyncReadExt;
 
 use crate::{
-    id::new_id,
+    id::{looks_like_id, new_id},
     provider::{wait_until_reachable, CreatedServer},
 };
 
-use super::Provider;
+use super::{sweep, Provider};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ScalewayConfig {
@@ -39,6 +39,9 @@ impl ScalewayProvider {
     }
 }
 
+/// Every server phalanx creates carries this tag, so that listing never sees anything else.
+const TAG: &str = "phalanx";
+
 static RECENT_IDS: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
 fn add_recent(s: &str) {
@@ -63,6 +66,7 @@ impl Provider for ScalewayProvider {
             "image": self.cfg.image,
             "enable_ipv6": false,
             "dynamic_ip_required": true,
+            "tags": [TAG],
         });
 
         let id = phalanx_id.to_string();
@@ -120,6 +124,24 @@ impl Provider for ScalewayProvider {
     async fn retain_by_id(
         &self,
         pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, false).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, true).await
+    }
+}
+
+impl ScalewayProvider {
+    /// Deletes the servers whose bridge IDs fail the predicate, among the servers carrying our tag, or on a legacy sweep among the untagged ones named after bridges.
+    async fn sweep_servers(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+        legacy: bool,
     ) -> anyhow::Result<()> {
         let cfg = self.cfg.clone();
         let base_url = format!(
@@ -128,7 +150,13 @@ impl Provider for ScalewayProvider {
         );
 
         for current_page in 1.. {
-            let url = format!("{}?per_page=10&page={}", base_url, current_page);
+            // untagged servers cannot be filtered for, so a legacy sweep pages through everything
+            let filter = if legacy {
+                String::new()
+            } else {
+                format!("tags={TAG}&")
+            };
+            let url = format!("{base_url}?{filter}per_page=10&page={current_page}");
             let resp = Request::get(&url)
                 .header("X-Auth-Token", &cfg.secret_key)
                 .body("")?
@@ -154,9 +182,21 @@ impl Provider for ScalewayProvider {
                     .as_str()
                     .ok_or(anyhow::Error::msg("No server name found"))?;
 
-                if !pred(server_name.to_string()) && !check_recent(server_name) {
-                    log::debug!("SCALEWAY DELETING {}", server_name);
-                    delete_server(&cfg, server_id).await?;
+                let tagged = server["tags"]
+                    .as_array()
+                    .is_some_and(|tags| tags.iter().any(|t| t == TAG));
+
+                let ours = if legacy {
+                    !tagged && looks_like_id(server_name)
+                } else {
+                    tagged
+                };
+                if ours && !pred(server_name.to_string()) && !check_recent(server_name) {
+                    sweep(
+                        &format!("scaleway server {server_name}"),
+                        delete_server(&cfg, server_id),
+                    )
+                    .await?;
                 }
             }
         }
@@ -7,13 +7,22 @@ use isahc::{http::StatusCode, AsyncReadResponseExt, HttpClient};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use crate::{id::new_id, provider::CreatedServer};
+use crate::{
+    id::{looks_like_id, new_id},
+    provider::CreatedServer,
+};
 
-use super::{wait_until_reachable, Provider};
+use super::{sweep, wait_until_reachable, Provider};
 
 const API: &str = "https://api.serverspace.io/api/v1";
 static CREATING: LazyLock<DashSet<String>> = LazyLock::new(DashSet::new);
 
+/// Servers have nowhere to put a tag, so every name phalanx picks starts with this.
+const NAME_PREFIX: &str = "phalanx-";
+
+/// What older versions named their servers with instead, which is too generic to trust on a shared account.
+const LEGACY_PREFIX: &str = "bridge-";
+
 /* ---------- user-supplied config ---------- */
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ServerSpaceConfig {
@@ -95,7 +104,7 @@ impl ServerSpaceProvider {
 impl Provider for ServerSpaceProvider {
     async fn create_server(&self) -> anyhow::Result<CreatedServer> {
         let id = new_id();
-        let label = format!("bridge-{id}");
+        let label = format!("{NAME_PREFIX}{id}");
         let body = json!({
             "location_id":  self.cfg.location_id,
             "image_id":     self.cfg.image_id,
@@ -168,20 +177,50 @@ impl Provider for ServerSpaceProvider {
     async fn retain_by_id(
         &self,
         pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, false).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> anyhow::Result<()> {
+        self.sweep_servers(pred, true).await
+    }
+}
+
+impl ServerSpaceProvider {
+    /// Deletes the servers whose bridge IDs fail the predicate, among the ones with our name prefix, or on a legacy sweep among the ones with the old generic prefix.
+    async fn sweep_servers(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+        legacy: bool,
     ) -> anyhow::Result<()> {
         for s in self.list_servers().await? {
             let srv_id = s["id"].as_str().context("no srv id")?.to_string();
             let label = s["name"].as_str().unwrap_or("").to_string();
-            let short = label.strip_prefix("bridge-").unwrap_or(&label).to_string();
-
-            if !pred(short)
+            let short = if legacy {
+                label
+                    .strip_prefix(LEGACY_PREFIX)
+                    .filter(|id| looks_like_id(id))
+            } else {
+                label.strip_prefix(NAME_PREFIX)
+            };
+            let Some(short) = short else {
+                continue;
+            };
+
+            if !pred(short.to_string())
                 && !CREATING.contains(&srv_id)
-                && s["nics"].as_array().is_some_and(|n| {
-                    n.iter().any(|n| n["network_type"] == "PublicShared")
-                })
+                && s["nics"]
+                    .as_array()
+                    .is_some_and(|n| n.iter().any(|n| n["network_type"] == "PublicShared"))
             {
-                log::debug!("Serverspace: deleting {srv_id} ({label})");
-                self.delete(&srv_id).await?;
+                sweep(
+                    &format!("serverspace server {srv_id} ({label})"),
+                    self.delete(&srv_id),
+                )
+                .await?;
             }
         }
         Ok(())
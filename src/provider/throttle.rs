@@ -0,0 +1,225 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use super::{CreatedServer, Provider};
+use crate::{
+    config::CreateLimit,
+    metrics::{PROVIDER_HEALTHY, SERVERS_CREATED},
+};
+
+/// The wait after the first failure, doubled on every failure after that.
+const BASE_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Token buckets, keyed by provider account.
+static BUCKETS: Lazy<DashMap<String, Arc<Mutex<TokenBucket>>>> = Lazy::new(DashMap::new);
+
+/// How a provider has been doing lately, from best to worst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Healthy,
+    /// Failed recently, so creates wait a while first.
+    BackingOff,
+    /// Failed too often, so creates are refused until the breaker cools down.
+    Unhealthy,
+}
+
+/// The health of every provider, keyed by group and provider.
+static HEALTH: Lazy<DashMap<(String, String), Health>> = Lazy::new(DashMap::new);
+
+/// Forgets the health of every provider of a group, for when its providers change.
+pub fn forget_health(group: &str) {
+    HEALTH.retain(|(g, _), _| g != group);
+}
+
+/// The health of a provider of a group. Providers that were never tried count as healthy.
+pub fn health(group: &str, provider: &str) -> Health {
+    HEALTH
+        .get(&(group.to_string(), provider.to_string()))
+        .map(|h| *h)
+        .unwrap_or(Health::Healthy)
+}
+
+/// The health of every provider of a group that was tried so far.
+pub fn group_health(group: &str) -> BTreeMap<String, Health> {
+    HEALTH
+        .iter()
+        .filter(|e| e.key().0 == group)
+        .map(|e| (e.key().1.clone(), *e.value()))
+        .collect()
+}
+
+struct TokenBucket {
+    tokens: f64,
+    burst: f64,
+    per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Takes a token, or returns how long until one is available.
+    fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        self.tokens = (self.tokens
+            + now.duration_since(self.last_refill).as_secs_f64() * self.per_sec)
+            .min(self.burst);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec))
+        }
+    }
+}
+
+#[derive(Default)]
+struct Backoff {
+    consecutive_failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// Rate-limits server creation on a provider, backing off on consecutive failures and giving up on the provider for a while once they pile up.
+pub struct Throttled<T: Provider> {
+    group: String,
+    provider: String,
+    limit: CreateLimit,
+    bucket: Arc<Mutex<TokenBucket>>,
+    backoff: Mutex<Backoff>,
+    inner: T,
+}
+
+impl<T: Provider> Throttled<T> {
+    /// Wraps a provider of the given group. Every provider on the same account shares one token bucket, which takes on the latest limit, since the config makes sure every group on the account agrees on it.
+    pub fn new(group: &str, name: &str, account: &str, limit: CreateLimit, provider: T) -> Self {
+        let (burst, per_sec) = (limit.burst.max(1) as f64, limit.per_minute / 60.0);
+        let bucket = BUCKETS
+            .entry(account.to_string())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(TokenBucket {
+                    tokens: limit.burst as f64,
+                    burst,
+                    per_sec,
+                    last_refill: Instant::now(),
+                }))
+            })
+            .clone();
+        {
+            let mut bucket = bucket.lock();
+            if bucket.burst != burst || bucket.per_sec != per_sec {
+                log::info!("{group}: {name} changes the create limit of its account");
+                bucket.tokens = bucket.tokens.min(burst);
+                bucket.burst = burst;
+                bucket.per_sec = per_sec;
+            }
+        }
+        Self {
+            group: group.to_string(),
+            provider: name.to_string(),
+            limit,
+            bucket,
+            backoff: Default::default(),
+            inner: provider,
+        }
+    }
+
+    fn tripped(&self, failures: u32) -> bool {
+        failures >= self.limit.breaker_threshold
+    }
+
+    fn set_health(&self, health: Health) {
+        HEALTH.insert((self.group.clone(), self.provider.clone()), health);
+        PROVIDER_HEALTHY
+            .with_label_values(&[&self.group, &self.provider])
+            .set((health != Health::Unhealthy) as i64);
+    }
+
+    fn record(&self, success: bool) {
+        SERVERS_CREATED
+            .with_label_values(&[
+                &self.group,
+                &self.provider,
+                if success { "success" } else { "failure" },
+            ])
+            .inc();
+        let mut backoff = self.backoff.lock();
+        if success {
+            if self.tripped(backoff.consecutive_failures) {
+                log::info!("{}: {} is healthy again", self.group, self.provider);
+            }
+            *backoff = Backoff::default();
+            self.set_health(Health::Healthy);
+            return;
+        }
+        backoff.consecutive_failures += 1;
+        let wait = if self.tripped(backoff.consecutive_failures) {
+            log::warn!(
+                "{}: {} failed {} times in a row, marking it unhealthy",
+                self.group,
+                self.provider,
+                backoff.consecutive_failures
+            );
+            self.set_health(Health::Unhealthy);
+            Duration::from_secs(self.limit.breaker_cooldown_secs)
+        } else {
+            self.set_health(Health::BackingOff);
+            (BASE_BACKOFF * 2u32.pow(backoff.consecutive_failures.min(16) - 1))
+                .min(Duration::from_secs(self.limit.max_backoff_secs))
+        };
+        backoff.retry_at = Some(Instant::now() + wait);
+    }
+}
+
+#[async_trait]
+impl<T: Provider> Provider for Throttled<T> {
+    async fn create_server(&self) -> Result<CreatedServer> {
+        loop {
+            let (failures, retry_at) = {
+                let backoff = self.backoff.lock();
+                (backoff.consecutive_failures, backoff.retry_at)
+            };
+            if let Some(retry_at) = retry_at.filter(|t| *t > Instant::now()) {
+                if self.tripped(failures) {
+                    anyhow::bail!(
+                        "provider is unhealthy after {failures} failures, not trying again for {}s",
+                        (retry_at - Instant::now()).as_secs()
+                    )
+                }
+                smol::Timer::at(retry_at).await;
+                continue;
+            }
+            let wait = self.bucket.lock().take();
+            match wait {
+                // check the backoff again, since another create may have failed in the meantime
+                Some(wait) => {
+                    smol::Timer::after(wait).await;
+                }
+                None => break,
+            }
+        }
+        let result = self.inner.create_server().await;
+        self.record(result.is_ok());
+        result
+    }
+
+    async fn retain_by_id(&self, pred: Box<dyn Fn(String) -> bool + Send + 'static>) -> Result<()> {
+        self.inner.retain_by_id(pred).await
+    }
+
+    async fn retain_legacy_by_id(
+        &self,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+    ) -> Result<()> {
+        self.inner.retain_legacy_by_id(pred).await
+    }
+}
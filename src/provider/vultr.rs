@@ -1,13 +1,14 @@
-use std::time::Duration;
+use std::{sync::LazyLock, time::Duration};
 
 use anyhow::Context;
 use async_trait::async_trait;
+use dashmap::DashSet;
 use isahc::AsyncReadResponseExt;
 use serde::{Deserialize, Serialize};
 
 use crate::{id::new_id, provider::CreatedServer};
 
-use super::Provider;
+use super::{sweep, Provider};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct VultrConfig {
@@ -56,11 +57,17 @@ struct ServerDescriptor {
     main_ip: Option<String>,
 }
 
+/// Bridges that are still being created, which must survive a sweep even though they are not in the database yet.
+static CREATING: LazyLock<DashSet<String>> = LazyLock::new(DashSet::new);
+
 #[async_trait]
 impl Provider for VultrProvider {
     async fn create_server(&self) -> anyhow::Result<CreatedServer> {
         let id = new_id();
-        let id = id.to_string();
+        CREATING.insert(id.clone());
+        scopeguard::defer!({
+            CREATING.remove(&id);
+        });
         let cfg = self.cfg.clone();
         let client = self.client.clone();
         let req = CreateServerArgs {
@@ -85,12 +92,12 @@ impl Provider for VultrProvider {
         loop {
             if let Some(server) = list_all(client.clone()).await?.into_iter().find(|server| {
                 server.label == vultrify_id(&id)
-                    && server.main_ip.is_some()
+                    && server.ip().is_some()
                     && server.status == "active"
             }) {
                 return Ok(CreatedServer {
-                    ip_addr: server.main_ip.unwrap(),
-                    id,
+                    ip_addr: server.ip().unwrap().to_string(),
+                    id: id.clone(),
                 });
             }
             smol::Timer::after(Duration::from_secs(1)).await;
@@ -99,28 +106,74 @@ impl Provider for VultrProvider {
 
     async fn retain_by_id(
         &self,
-        _pred: Box<dyn Fn(String) -> bool + Send + 'static>,
+        pred: Box<dyn Fn(String) -> bool + Send + 'static>,
     ) -> anyhow::Result<()> {
-        log::warn!("vultr no delete yet");
+        for server in list_all(self.client.clone()).await? {
+            let Some(id) = server.label.strip_prefix("vultr-phalanx-") else {
+                continue;
+            };
+            if !pred(id.to_string()) && !CREATING.contains(id) {
+                sweep(&format!("vultr server {}", server.label), async {
+                    let mut resp = self
+                        .client
+                        .delete_async(format!("https://api.vultr.com/v2/instances/{}", server.id))
+                        .await?;
+                    if !resp.status().is_success() {
+                        let r = resp.text().await?;
+                        anyhow::bail!("non-success while deleting: {:?} {r}", resp.status())
+                    }
+                    Ok(())
+                })
+                .await?;
+            }
+        }
         Ok(())
     }
 }
 
+impl ServerDescriptor {
+    /// The public address, which pending instances report as `0.0.0.0`.
+    fn ip(&self) -> Option<&str> {
+        self.main_ip.as_deref().filter(|ip| *ip != "0.0.0.0")
+    }
+}
+
 fn vultrify_id(id: &str) -> String {
     format!("vultr-phalanx-{id}")
 }
 
-/// List all the servers.
+/// List all the servers, following the cursor through every page.
 async fn list_all(client: isahc::HttpClient) -> anyhow::Result<Vec<ServerDescriptor>> {
     #[derive(Clone, Debug, Deserialize)]
     struct Resp {
         instances: Vec<ServerDescriptor>,
+        meta: Meta,
+    }
+    #[derive(Clone, Debug, Deserialize)]
+    struct Meta {
+        links: Links,
+    }
+    #[derive(Clone, Debug, Deserialize)]
+    struct Links {
+        #[serde(default)]
+        next: String,
+    }
+    let mut instances = vec![];
+    let mut cursor = String::new();
+    loop {
+        let page: Resp = client
+            .get_async(format!(
+                "https://api.vultr.com/v2/instances?{}",
+                serde_urlencoded::to_string([("per_page", "500"), ("cursor", &cursor)])?
+            ))
+            .await?
+            .json()
+            .await
+            .context("could not decode list")?;
+        instances.extend(page.instances);
+        if page.meta.links.next.is_empty() {
+            return Ok(instances);
+        }
+        cursor = page.meta.links.next;
     }
-    let haha: Resp = client
-        .get_async("https://api.vultr.com/v2/instances")
-        .await?
-        .json()
-        .await
-        .context("could not decode list")?;
-    Ok(haha.instances)
 }
@@ -1,35 +1,423 @@
-use std::{process::Stdio, time::Duration};
+use std::{
+    io::Read,
+    net::{TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
+use base64::Engine;
+use dashmap::DashMap;
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
 use smol::lock::Semaphore;
 use smol_timeout::TimeoutExt;
 
-pub async fn ssh_execute(host: &str, cmd: &str) -> anyhow::Result<String> {
+use crate::{database::DATABASE, metrics::SSH_FAILURES};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Pinning stops retrying once the wait between tries would reach this.
+const PIN_GIVE_UP: Duration = Duration::from_secs(60);
+
+/// Commands that may run longer than this get a connection of their own, so that they do not hold up the quick probes of the same host.
+const LONG_COMMAND: Duration = Duration::from_secs(120);
+
+/// Cached connections that went unused for this long are closed, since their bridges are likely gone.
+const SESSION_IDLE: Duration = Duration::from_secs(600);
+
+/// What libssh2 returns when a non-blocking call would block.
+const EAGAIN: ssh2::ErrorCode = ssh2::ErrorCode::Session(-37);
+
+/// What a command run on a bridge produced.
+#[derive(Clone, Debug)]
+pub struct CommandOutput {
+    pub exit_status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// Whether the command exited with status 0.
+    pub fn success(&self) -> bool {
+        self.exit_status == 0
+    }
+}
+
+/// A single SSH connection to a bridge, reused by every command sent to it. The session is non-blocking once logged in, and locked only for each call into libssh2, so that commands to the same host interleave.
+struct SshSession {
+    session: Mutex<ssh2::Session>,
+    host_key: String,
+    last_used: Mutex<Instant>,
+}
+
+/// The bridge presented a different host key from the one pinned when it was created.
+#[derive(Debug)]
+pub struct HostKeyMismatch {
+    pub host: String,
+    pub pinned: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for HostKeyMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "host key of {} changed from {} to {}",
+            self.host, self.pinned, self.actual
+        )
+    }
+}
+
+impl std::error::Error for HostKeyMismatch {}
+
+/// Marks failures that happened before the command started, which are safe to retry on a new connection.
+#[derive(Debug)]
+struct NotStarted;
+
+impl std::fmt::Display for NotStarted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "command never started")
+    }
+}
+
+/// Open connections, keyed by host.
+static SESSIONS: Lazy<DashMap<String, Arc<SshSession>>> = Lazy::new(DashMap::new);
+
+impl SshSession {
+    fn connect(host: &str) -> anyhow::Result<Self> {
+        let addr = (host, 22)
+            .to_socket_addrs()?
+            .next()
+            .context("could not resolve host")?;
+        let tcp = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        let mut session = ssh2::Session::new()?;
+        session.set_compress(true);
+        session.set_timeout(CONNECT_TIMEOUT.as_millis() as u32);
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+        let host_key = session
+            .host_key_hash(ssh2::HashType::Sha256)
+            .context("no host key")?
+            .iter()
+            .fold("SHA256:".to_string(), |acc, b| format!("{acc}{b:02x}"));
+        authenticate(&session).with_context(|| format!("cannot log into root@{host}"))?;
+        session.set_keepalive(true, 30);
+        session.set_blocking(false);
+        Ok(Self {
+            session: Mutex::new(session),
+            host_key,
+            last_used: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Calls into libssh2 until it stops asking to be called again, holding the session only for each call.
+    async fn retry<R>(
+        &self,
+        mut op: impl FnMut(&ssh2::Session) -> Result<R, ssh2::Error>,
+    ) -> Result<R, ssh2::Error> {
+        let mut pace = Pace::default();
+        loop {
+            let result = op(&self.session.lock());
+            match result {
+                Err(err) if err.code() == EAGAIN => pace.idle().await,
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs a command, reading its stdout and stderr together, since a command that fills one while the other is read to its end would stall.
+    async fn run(&self, cmd: &str) -> anyhow::Result<CommandOutput> {
+        let mut channel = self
+            .retry(|session| session.channel_session())
+            .await
+            .map_err(|err| anyhow::Error::new(err).context(NotStarted))?;
+        self.retry(|_| channel.exec(cmd))
+            .await
+            .map_err(|err| anyhow::Error::new(err).context(NotStarted))?;
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        let mut pace = Pace::default();
+        loop {
+            let (finished, progressed) = {
+                let _session = self.session.lock();
+                let finished = channel.eof();
+                let progressed = read_available(&mut channel.stream(0), &mut stdout)?
+                    | read_available(&mut channel.stderr(), &mut stderr)?;
+                (finished, progressed)
+            };
+            if finished {
+                break;
+            }
+            if progressed {
+                pace = Pace::default();
+            } else {
+                pace.idle().await;
+            }
+        }
+        self.retry(|_| channel.wait_close()).await?;
+        Ok(CommandOutput {
+            exit_status: channel.exit_status()?,
+            stdout: String::from_utf8_lossy(&stdout).into(),
+            stderr: String::from_utf8_lossy(&stderr).into(),
+        })
+    }
+}
+
+/// Waits between polls of a non-blocking session, longer the longer it stays quiet.
+struct Pace(Duration);
+
+impl Default for Pace {
+    fn default() -> Self {
+        Self(Duration::from_millis(5))
+    }
+}
+
+impl Pace {
+    async fn idle(&mut self) {
+        smol::Timer::after(self.0).await;
+        self.0 = (self.0 * 2).min(Duration::from_millis(200));
+    }
+}
+
+/// Reads whatever the stream has ready, returning whether there was anything.
+fn read_available(stream: &mut impl Read, out: &mut Vec<u8>) -> anyhow::Result<bool> {
+    let mut buf = [0u8; 32 * 1024];
+    let mut progressed = false;
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(progressed),
+            Ok(n) => {
+                out.extend_from_slice(&buf[..n]);
+                progressed = true;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(progressed),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Logs in as root with the agent if there is one, and otherwise with the default identity files.
+fn authenticate(session: &ssh2::Session) -> anyhow::Result<()> {
+    if session.userauth_agent("root").is_ok() {
+        return Ok(());
+    }
+    let ssh_dir = PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".ssh");
+    for name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+        let path = ssh_dir.join(name);
+        if path.exists()
+            && session
+                .userauth_pubkey_file("root", None, &path, None)
+                .is_ok()
+        {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("no usable SSH identity")
+}
+
+async fn connect(host: &str) -> anyhow::Result<Arc<SshSession>> {
+    let owned_host = host.to_string();
+    Ok(Arc::new(
+        smol::unblock(move || SshSession::connect(&owned_host)).await?,
+    ))
+}
+
+/// A connection of its own for a long command, which is closed once the command is done.
+async fn own_session(host: &str) -> anyhow::Result<Arc<SshSession>> {
+    let session = connect(host).await?;
+    verify_host_key(host, &session.host_key).await?;
+    Ok(session)
+}
+
+async fn session(host: &str) -> anyhow::Result<Arc<SshSession>> {
+    if let Some(session) = SESSIONS.get(host) {
+        *session.last_used.lock() = Instant::now();
+        return Ok(session.clone());
+    }
+    let session = connect(host).await?;
+    verify_host_key(host, &session.host_key).await?;
+    // bridges come and go, so let go of the connections to the ones nothing talked to lately
+    SESSIONS.retain(|_, s| s.last_used.lock().elapsed() < SESSION_IDLE);
+    SESSIONS.insert(host.to_string(), session.clone());
+    Ok(session)
+}
+
+/// Checks a host key against the pinned one, trusting it if nothing was pinned yet.
+async fn verify_host_key(host: &str, actual: &str) -> anyhow::Result<()> {
+    match DATABASE.host_key(host).await? {
+        Some(pinned) if pinned != actual => Err(HostKeyMismatch {
+            host: host.to_string(),
+            pinned,
+            actual: actual.to_string(),
+        }
+        .into()),
+        Some(_) => Ok(()),
+        None => {
+            log::info!("trusting host key {actual} of {host} on first use");
+            DATABASE.set_host_key(host, actual).await
+        }
+    }
+}
+
+/// Pins the host key of a server that was just created, replacing whatever was pinned for a previous server at the same address.
+pub async fn pin_host_key(host: &str) -> anyhow::Result<()> {
+    #[cfg(test)]
+    if let Some(host_key) = crate::provider::mock::host_key(host) {
+        return DATABASE.set_host_key(host, &host_key).await;
+    }
+
+    // a freshly booted server may still be starting sshd, so give it a few tries
+    let mut delay = Duration::from_secs(2);
+    let session = loop {
+        match connect(host).await {
+            Ok(session) => break session,
+            Err(err) if delay < PIN_GIVE_UP => {
+                log::debug!(
+                    "cannot reach {host} to pin its host key, retrying in {delay:?}: {err:?}"
+                );
+                smol::Timer::after(delay).await;
+                delay *= 2;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    // a server that was bootstrapped over OpenSSH must still be the one that bootstrap talked to
+    if let Some(seen) = bootstrap_host_keys(host)? {
+        if !seen.contains(&session.host_key) {
+            anyhow::bail!(
+                "{host} presents host key {}, which is none of the {} keys seen while bootstrapping it",
+                session.host_key,
+                seen.len()
+            )
+        }
+    }
+    DATABASE.set_host_key(host, &session.host_key).await?;
+    log::debug!("pinned host key {} of {host}", session.host_key);
+    forget_bootstrap_host_keys(host);
+    SESSIONS.insert(host.to_string(), session);
+    Ok(())
+}
+
+/// The known hosts file that the bootstrap of the server at the given address uses.
+fn bootstrap_known_hosts(host: &str) -> PathBuf {
+    PathBuf::from(std::env::var("HOME").unwrap_or_default())
+        .join(".ssh")
+        .join("phalanx_known_hosts")
+        .join(host)
+}
+
+/// Options for the OpenSSH client, for the commands that bootstrap root access on a server that was just created. The key it presents is trusted on first use, but then held to for the rest of the bootstrap, in a known hosts file of its own that starts out empty since the address may have belonged to an earlier server. Every other key the server has is recorded there too, so that pinning can check whichever one it is shown.
+pub fn bootstrap_ssh_options(host: &str) -> anyhow::Result<String> {
+    let known_hosts = bootstrap_known_hosts(host);
+    if let Some(dir) = known_hosts.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match std::fs::remove_file(&known_hosts) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    Ok(format!(
+        "-o StrictHostKeyChecking=accept-new -o UpdateHostKeys=yes -o UserKnownHostsFile={}",
+        known_hosts.display()
+    ))
+}
+
+/// The fingerprints of the host keys that the bootstrap of the server at the given address saw, or None if it was not bootstrapped over OpenSSH.
+fn bootstrap_host_keys(host: &str) -> anyhow::Result<Option<Vec<String>>> {
+    let known_hosts = match std::fs::read_to_string(bootstrap_known_hosts(host)) {
+        Ok(known_hosts) => known_hosts,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    // each line is the host, the key type and the key
+    Ok(Some(
+        known_hosts
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(2))
+            .filter_map(|key| base64::engine::general_purpose::STANDARD.decode(key).ok())
+            .map(|key| format!("SHA256:{}", hex::encode(Sha256::digest(key))))
+            .collect(),
+    ))
+}
+
+/// Deletes what the bootstrap of the server at the given address learned, once its key is pinned or the server is torn down.
+pub fn forget_bootstrap_host_keys(host: &str) {
+    match std::fs::remove_file(bootstrap_known_hosts(host)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            log::warn!(
+                "could not delete the bootstrap known hosts of {host}: {:?}",
+                err
+            )
+        }
+        _ => {}
+    }
+}
+
+/// If the error came from a host key mismatch, marks the bridge as compromised so that it gets retired.
+pub async fn retire_if_compromised(bridge_id: &str, err: &anyhow::Error) -> anyhow::Result<()> {
+    if let Some(mismatch) = err.downcast_ref::<HostKeyMismatch>() {
+        log::error!("POSSIBLE MITM on {bridge_id}: {mismatch}; retiring it");
+        DATABASE.set_status(bridge_id, "compromised").await?;
+    }
+    Ok(())
+}
+
+/// Drops a connection that misbehaved, so that the next command reconnects.
+fn forget(host: &str, session: &Arc<SshSession>) {
+    SESSIONS.remove_if(host, |_, s| Arc::ptr_eq(s, session));
+}
+
+/// Runs a command as root on the given host, giving up after the timeout. A non-zero exit status is not an error; check [CommandOutput::success].
+pub async fn ssh_execute(
+    host: &str,
+    cmd: &str,
+    timeout: Duration,
+) -> anyhow::Result<CommandOutput> {
+    #[cfg(test)]
+    if let Some((host_key, output)) = crate::provider::mock::intercept_ssh(host, cmd) {
+        verify_host_key(host, &host_key).await?;
+        return Ok(output);
+    }
+
     static SSH_SEMAPHORE: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(128));
     let _guard = SSH_SEMAPHORE.acquire().await;
 
-    let status = smol::process::Command::new("ssh")
-        .arg("-C")
-        .arg("-o")
-        .arg("ConnectTimeout=300")
-        .arg("-o")
-        .arg("StrictHostKeyChecking=no")
-        .arg("-o")
-        .arg("UserKnownHostsFile=/dev/null")
-        .arg(format!("root@{host}"))
-        .arg(cmd)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?
-        .output()
-        .timeout(Duration::from_secs(3600))
-        .await
-        .context("timeout in SSH after 3600 secs")??;
-
-    // if !status.status.success() {
-    //     anyhow::bail!("failed with status {:?}", status)
-    // }
-    log::trace!("ssh <{host}> {cmd}");
-    Ok(String::from_utf8_lossy(&status.stdout).into())
+    // a cached connection may have died since it was last used, so give a fresh one a second chance
+    for attempt in 0..2 {
+        let session = if timeout > LONG_COMMAND {
+            own_session(host).await
+        } else {
+            session(host).await
+        };
+        let session = match session {
+            Ok(session) => session,
+            Err(err) => {
+                SSH_FAILURES.with_label_values(&["error"]).inc();
+                return Err(err);
+            }
+        };
+        let result = session.run(cmd).timeout(timeout).await;
+        match result {
+            Some(Ok(output)) => {
+                log::trace!("ssh <{host}> {cmd} => {}", output.exit_status);
+                return Ok(output);
+            }
+            Some(Err(err)) => {
+                forget(host, &session);
+                if attempt > 0 || err.downcast_ref::<NotStarted>().is_none() {
+                    SSH_FAILURES.with_label_values(&["error"]).inc();
+                    return Err(err);
+                }
+                log::debug!("ssh <{host}> could not start a command, reconnecting: {err:?}");
+            }
+            None => {
+                forget(host, &session);
+                SSH_FAILURES.with_label_values(&["timeout"]).inc();
+                anyhow::bail!("timeout in SSH after {} secs", timeout.as_secs())
+            }
+        }
+    }
+    unreachable!()
 }